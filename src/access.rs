@@ -0,0 +1,181 @@
+//! Access log for every request.
+//!
+//! Entries are buffered in memory and flushed into `access_log` table in batch, to avoid touching
+//! the global database mutex on every request.
+use crate::db;
+use crate::remote::{self, Peer};
+use crate::ticker::Ticker;
+use axum::body::HttpBody;
+use axum::http::header::CONTENT_LENGTH;
+use axum::http::Request;
+use axum::middleware::Next;
+use axum::response::Response;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Instant, UNIX_EPOCH};
+use tokio::sync::broadcast::{self, Sender};
+
+#[derive(Clone, Serialize)]
+pub struct Entry {
+    /// Unix timestamp in seconds.
+    pub time: u64,
+    pub ip: String,
+    pub tls_version: String,
+    pub alpn: String,
+    pub method: String,
+    pub path: String,
+    pub status: u16,
+    /// Latency in microseconds, until the response head is ready.
+    pub latency: u64,
+    /// Response body size, `-1` if unknown (such as streaming).
+    pub bytes: i64,
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} | {} | {} {} | {} {} | {} | {}us | {}B",
+            self.time,
+            self.ip,
+            self.tls_version,
+            self.alpn,
+            self.method,
+            self.path,
+            self.status,
+            self.latency,
+            self.bytes
+        )
+    }
+}
+
+fn db_init() {
+    db! {"
+        CREATE TABLE IF NOT EXISTS access_log
+        (time INTEGER, ip TEXT, tls_version TEXT, alpn TEXT, method TEXT,
+        path TEXT, status INTEGER, latency INTEGER, bytes INTEGER)
+    "}
+    .unwrap();
+}
+fn db_insert(e: Entry) {
+    db! {"
+        INSERT INTO access_log
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
+    ", [e.time, e.ip, e.tls_version, e.alpn, e.method, e.path, e.status, e.latency, e.bytes]}
+    .unwrap();
+}
+fn db_search(pattern: &str, limit: u32) -> Vec<Entry> {
+    let rows = db! {"
+        SELECT * FROM access_log
+        WHERE ip LIKE ?1 ESCAPE '\\' OR path LIKE ?1 ESCAPE '\\'
+            OR method LIKE ?1 ESCAPE '\\' OR status LIKE ?1 ESCAPE '\\'
+        ORDER BY time DESC
+        LIMIT ?2
+    ", [pattern, limit], (0, 1, 2, 3, 4, 5, 6, 7, 8)}
+    .unwrap();
+    let to_entry = |(time, ip, tls_version, alpn, method, path, status, latency, bytes)| Entry {
+        time,
+        ip,
+        tls_version,
+        alpn,
+        method,
+        path,
+        status,
+        latency,
+        bytes,
+    };
+    rows.into_iter().map(to_entry).collect()
+}
+fn db_clean() {
+    db! {"
+        DELETE FROM access_log
+        WHERE strftime('%s','now') - time > 3600 * 24 * 7
+    "}
+    .unwrap();
+}
+
+static BUF: Mutex<Vec<Entry>> = Mutex::new(Vec::new());
+static TAIL: Lazy<Sender<Entry>> = Lazy::new(|| broadcast::channel(64).0);
+
+fn flush() {
+    static DB_INIT: Lazy<()> = Lazy::new(db_init);
+    Lazy::force(&DB_INIT);
+    let entries = std::mem::take(&mut *BUF.lock().unwrap());
+    for e in entries {
+        db_insert(e);
+    }
+}
+
+/// The middleware, apply it to the outermost.
+///
+/// # Example
+///
+/// ```
+/// let app = Router::new().layer(axum::middleware::from_fn(access::layer));
+/// ```
+pub async fn layer<B>(req: Request<B>, next: Next<B>) -> Response {
+    let instant = Instant::now();
+    let ip = remote::resolve(req.headers(), req.extensions()).to_string();
+    let peer = req.extensions().get::<Peer>().copied();
+    let (tls_version, alpn) = peer.map_or(("", ""), |v| (v.tls_version, v.alpn));
+    let method = req.method().to_string();
+    let path = req.uri().path().to_string();
+    let response = next.run(req).await;
+    let bytes = match response.body().size_hint().exact() {
+        Some(v) => v as i64,
+        None => response
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok()?.parse().ok())
+            .unwrap_or(-1),
+    };
+    let entry = Entry {
+        time: UNIX_EPOCH.elapsed().unwrap().as_secs(),
+        ip,
+        tls_version: tls_version.to_string(),
+        alpn: alpn.to_string(),
+        method,
+        path,
+        status: response.status().as_u16(),
+        latency: instant.elapsed().as_micros() as _,
+        bytes,
+    };
+    TAIL.send(entry.clone()).ok(); // no receivers is fine
+    let len = {
+        let mut buf = BUF.lock().unwrap();
+        buf.push(entry);
+        buf.len()
+    };
+    if len >= 256 {
+        tokio::task::spawn_blocking(flush);
+    }
+    response
+}
+
+/// Search entries by IP, path, method or status, the newest first.
+pub fn search(keyword: &str, limit: u32) -> Vec<Entry> {
+    flush();
+    let keyword = keyword.replace('\\', "\\\\");
+    let keyword = keyword.replace('%', "\\%").replace('_', "\\_");
+    db_search(&format!("%{keyword}%"), limit)
+}
+
+/// Subscribe the new entries.
+pub fn tail() -> broadcast::Receiver<Entry> {
+    TAIL.subscribe()
+}
+
+static TICKER: Lazy<Ticker> = Lazy::new(|| Ticker::new_p8(&[(3, 10, 0)]));
+pub async fn tick() {
+    let clean = TICKER.tick();
+    tokio::task::spawn_blocking(move || {
+        flush();
+        if clean {
+            db_clean();
+        }
+    })
+    .await
+    .unwrap();
+}
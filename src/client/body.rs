@@ -0,0 +1,121 @@
+//! Read `hyper::Body` with size limit.
+use futures_core::{ready, Stream};
+use hyper::body::{Bytes, HttpBody};
+use hyper::Body;
+use std::fmt;
+use std::future::poll_fn;
+use std::pin::Pin;
+use std::string::FromUtf8Error;
+use std::task::{Context, Poll};
+
+/// The default limit of `Fetch`, 2 MiB.
+pub const DEFAULT_BODY_LIMIT: usize = 2048 * 1024;
+
+#[derive(Debug)]
+pub enum BodyError {
+    /// Exceeded the limit, in bytes.
+    TooLarge(usize),
+    Io(hyper::Error),
+    InvalidUtf8(FromUtf8Error),
+}
+
+impl fmt::Display for BodyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BodyError::TooLarge(limit) => write!(f, "body exceeds the limit of {limit} bytes"),
+            BodyError::Io(e) => write!(f, "read body failed: {e}"),
+            BodyError::InvalidUtf8(e) => write!(f, "body is not valid UTF-8: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for BodyError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BodyError::TooLarge(_) => None,
+            BodyError::Io(e) => Some(e),
+            BodyError::InvalidUtf8(e) => Some(e),
+        }
+    }
+}
+
+/// Read `hyper::Body` into `Vec<u8>`, at most `limit` bytes.
+pub async fn read_body(body: Body, limit: usize) -> Result<Vec<u8>, BodyError> {
+    BodyStream::new(body, limit).bytes().await
+}
+
+/// Read `hyper::Body` into `String`, at most `limit` bytes.
+pub async fn read_text(body: Body, limit: usize) -> Result<String, BodyError> {
+    BodyStream::new(body, limit).text().await
+}
+
+/// Stream of body chunks with size limit, for large downloads.
+///
+/// Ends after yielding the first error.
+///
+/// # Example
+///
+/// ```
+/// let mut stream = Fetch::new(uri).body_limit(64 << 20).stream().await?;
+/// while let Some(chunk) = poll_fn(|cx| Pin::new(&mut stream).poll_next(cx)).await {
+///     file.write_all(&chunk?).await?;
+/// }
+/// ```
+pub struct BodyStream {
+    body: Body,
+    limit: usize,
+    read: usize,
+    done: bool,
+}
+
+impl BodyStream {
+    pub fn new(body: Body, limit: usize) -> Self {
+        Self {
+            body,
+            limit,
+            read: 0,
+            done: false,
+        }
+    }
+
+    /// Read the remaining chunks into `Vec<u8>`.
+    pub async fn bytes(mut self) -> Result<Vec<u8>, BodyError> {
+        let mut v = Vec::new();
+        while let Some(bytes) = poll_fn(|cx| Pin::new(&mut self).poll_next(cx)).await {
+            v.extend_from_slice(&bytes?);
+        }
+        Ok(v)
+    }
+
+    /// Read the remaining chunks into `String`.
+    pub async fn text(self) -> Result<String, BodyError> {
+        String::from_utf8(self.bytes().await?).map_err(BodyError::InvalidUtf8)
+    }
+}
+
+impl Stream for BodyStream {
+    type Item = Result<Bytes, BodyError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.done {
+            return Poll::Ready(None);
+        }
+        if self.read == 0 && HttpBody::size_hint(&self.body).lower() > self.limit as u64 {
+            self.done = true; // fail fast by Content-Length
+            return Poll::Ready(Some(Err(BodyError::TooLarge(self.limit))));
+        }
+        let ret = match ready!(Pin::new(&mut self.body).poll_data(cx)) {
+            Some(Ok(bytes)) if self.read + bytes.len() > self.limit => {
+                Err(BodyError::TooLarge(self.limit))
+            }
+            Some(Ok(bytes)) => {
+                self.read += bytes.len();
+                return Poll::Ready(Some(Ok(bytes)));
+            }
+            Some(Err(e)) => Err(BodyError::Io(e)),
+            None => return Poll::Ready(None),
+        };
+        self.done = true;
+        Poll::Ready(Some(ret))
+    }
+}
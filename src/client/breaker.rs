@@ -0,0 +1,83 @@
+//! Per host circuit breaker, stop hammering a dead upstream.
+//!
+//! After `THRESHOLD` consecutive failures (network error or 5xx), the breaker opens and requests
+//! to the host fail fast. When cooled down, one request is let through as a trial, the breaker
+//! closes if succeeded, or opens again with doubled cooldown.
+use anyhow::{anyhow, Result};
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const THRESHOLD: u32 = 5;
+const BASE_COOLDOWN: Duration = Duration::from_secs(15);
+
+#[derive(Default)]
+struct State {
+    /// Consecutive failures.
+    failures: u32,
+    /// Times of opened since last closed.
+    trips: u32,
+    open_until: Option<Instant>,
+}
+
+static STATES: Lazy<Mutex<HashMap<String, State>>> = Lazy::new(Default::default);
+
+fn cooldown(trips: u32) -> Duration {
+    BASE_COOLDOWN * 2u32.pow(trips.saturating_sub(1).min(5)) // 8 minutes at most
+}
+
+/// Returns error if the breaker of host is open.
+pub fn check(host: &str) -> Result<()> {
+    let now = Instant::now();
+    let mut states = STATES.lock().unwrap();
+    let state = match states.get_mut(host) {
+        Some(v) => v,
+        None => return Ok(()),
+    };
+    match state.open_until {
+        Some(v) if now < v => Err(anyhow!("circuit breaker is open for {host}")),
+        Some(_) => {
+            // half open, block others until the trial finished
+            state.open_until = Some(now + cooldown(state.trips));
+            Ok(())
+        }
+        None => Ok(()),
+    }
+}
+
+/// Record the result of a request.
+pub fn record(host: &str, ok: bool) {
+    let mut states = STATES.lock().unwrap();
+    if ok {
+        states.remove(host);
+        return;
+    }
+    let state = states.entry(host.to_string()).or_default();
+    state.failures += 1;
+    if state.failures >= THRESHOLD {
+        state.trips += 1;
+        state.open_until = Some(Instant::now() + cooldown(state.trips));
+    }
+}
+
+/// Display the hosts which are failing, one per line.
+pub fn status() -> String {
+    let now = Instant::now();
+    let states = STATES.lock().unwrap();
+    let mut ret = String::new();
+    let mut hosts = states.keys().collect::<Vec<_>>();
+    hosts.sort();
+    for host in hosts {
+        let state = &states[host];
+        write!(ret, "{host} : {} failures", state.failures).unwrap();
+        match state.open_until {
+            Some(v) if now < v => writeln!(ret, ", open for {} s", (v - now).as_secs()),
+            Some(_) => writeln!(ret, ", half open"),
+            None => writeln!(ret, ", closed"),
+        }
+        .unwrap();
+    }
+    ret
+}
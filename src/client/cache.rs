@@ -0,0 +1,187 @@
+//! HTTP cache for `GET` requests, stored in `fetch_cache` table.
+//!
+//! Honors `Cache-Control` (`max-age`, `no-cache`, `no-store`) and `Expires`, revalidates by
+//! `If-None-Match` and `If-Modified-Since`. Entries are kept after expired, to be revalidated or
+//! served as stale content when the upstream fails, until not used for a month.
+//!
+//! Shared by all callers, so the `private` and `Vary: *` responses are not stored, and `Fetch`
+//! bypasses the cache for requests with credentials.
+use crate::db;
+use crate::ticker::Ticker;
+use hyper::header::{HeaderMap, HeaderName, HeaderValue, VARY, WARNING};
+use hyper::header::{CACHE_CONTROL, CONNECTION, CONTENT_LENGTH, ETAG, EXPIRES, LAST_MODIFIED};
+use hyper::header::{IF_MODIFIED_SINCE, IF_NONE_MATCH, SET_COOKIE, TRANSFER_ENCODING};
+use hyper::{Body, Response};
+use once_cell::sync::Lazy;
+use std::time::UNIX_EPOCH;
+
+fn db_init() {
+    db! {"
+        CREATE TABLE IF NOT EXISTS fetch_cache
+        (uri TEXT PRIMARY KEY, expires INTEGER, headers TEXT, body BLOB, time INTEGER)
+    "}
+    .unwrap();
+}
+fn db_set(uri: &str, expires: u64, headers: &str, body: &[u8]) {
+    db! {"
+        REPLACE INTO fetch_cache
+        VALUES (?1, ?2, ?3, ?4, strftime('%s','now'))
+    ", [uri, expires, headers, body]}
+    .unwrap();
+}
+fn db_touch(uri: &str, expires: u64) {
+    db! {"
+        UPDATE fetch_cache
+        SET expires = ?2, time = strftime('%s','now')
+        WHERE uri = ?1
+    ", [uri, expires]}
+    .unwrap();
+}
+fn db_get(uri: &str) -> Option<(u64, String, Vec<u8>)> {
+    db! {"
+        SELECT expires, headers, body FROM fetch_cache
+        WHERE uri = ?
+    ", [uri], ^(0, 1, 2)}
+    .ok()
+}
+fn db_clean() {
+    // not used for a month
+    db! {"
+        DELETE FROM fetch_cache
+        WHERE strftime('%s','now') - time > 3600 * 24 * 30
+    "}
+    .unwrap();
+}
+
+static DB_INIT: Lazy<()> = Lazy::new(db_init);
+
+fn now() -> u64 {
+    UNIX_EPOCH.elapsed().unwrap().as_secs()
+}
+
+pub struct Entry {
+    /// Unix timestamp in seconds.
+    expires: u64,
+    headers: HeaderMap,
+    body: Vec<u8>,
+}
+
+impl Entry {
+    pub fn is_fresh(&self) -> bool {
+        self.expires > now()
+    }
+
+    /// Headers for revalidation, `If-None-Match` and `If-Modified-Since`.
+    pub fn conditions(&self) -> impl Iterator<Item = (HeaderName, HeaderValue)> + '_ {
+        let etag = self.headers.get(ETAG).map(|v| (IF_NONE_MATCH, v));
+        let last_modified = self
+            .headers
+            .get(LAST_MODIFIED)
+            .map(|v| (IF_MODIFIED_SINCE, v));
+        let conditions = etag.into_iter().chain(last_modified);
+        conditions.map(|(k, v)| (k, v.clone()))
+    }
+
+    /// Build the response, mark with `Warning` header if stale.
+    pub fn into_response(self, stale: bool) -> Response<Body> {
+        let mut response = Response::new(Body::from(self.body));
+        *response.headers_mut() = self.headers;
+        if stale {
+            let v = HeaderValue::from_static("110 - \"Response is Stale\"");
+            response.headers_mut().insert(WARNING, v);
+        }
+        response
+    }
+}
+
+/// Get the entry of URI, fresh or not.
+pub fn get(uri: &str) -> Option<Entry> {
+    Lazy::force(&DB_INIT);
+    let (expires, text, body) = db_get(uri)?;
+    let mut headers = HeaderMap::new();
+    for line in text.lines() {
+        let (k, v) = line.split_once(": ")?;
+        headers.append(
+            HeaderName::try_from(k).ok()?,
+            HeaderValue::try_from(v).ok()?,
+        );
+    }
+    Some(Entry {
+        expires,
+        headers,
+        body,
+    })
+}
+
+/// The expiration time by response headers, `None` if should not be stored.
+fn expires(headers: &HeaderMap) -> Option<u64> {
+    // the entries are keyed by URI only, not by the request headers
+    let vary = headers.get_all(VARY).iter();
+    if vary
+        .flat_map(|v| v.to_str().unwrap_or("*").split(','))
+        .any(|v| v.trim() == "*")
+    {
+        return None;
+    }
+    let now = now();
+    let directives = headers.get_all(CACHE_CONTROL).iter();
+    let directives = directives.flat_map(|v| v.to_str().unwrap_or_default().split(','));
+    let mut max_age = None;
+    for directive in directives {
+        match directive.trim().to_ascii_lowercase().as_str() {
+            "no-store" | "private" => return None,
+            "no-cache" => return Some(now), // store, but always revalidate
+            v => {
+                if let Some(Ok(v)) = v.strip_prefix("max-age=").map(str::parse::<u64>) {
+                    max_age = Some(v);
+                }
+            }
+        }
+    }
+    if let Some(v) = max_age {
+        return Some(now + v);
+    }
+    let expires = headers.get(EXPIRES).and_then(|v| v.to_str().ok());
+    let expires = expires.and_then(|v| httpdate::parse_http_date(v).ok());
+    let expires = expires.and_then(|v| v.duration_since(UNIX_EPOCH).ok());
+    Some(expires.map_or(now, |v| v.as_secs()))
+}
+
+/// Store a `200 OK` response, the cookies and hop-by-hop headers are ignored.
+pub fn store(uri: &str, headers: &HeaderMap, body: &[u8]) {
+    Lazy::force(&DB_INIT);
+    let expires = match expires(headers) {
+        Some(v) => v,
+        None => return,
+    };
+    let mut text = String::new();
+    for (k, v) in headers {
+        if [SET_COOKIE, CONNECTION, TRANSFER_ENCODING, CONTENT_LENGTH].contains(k) {
+            continue;
+        }
+        if let Ok(v) = v.to_str() {
+            text += &format!("{k}: {v}\n");
+        }
+    }
+    db_set(uri, expires, &text, body);
+}
+
+/// Refresh the expiration time by a `304 Not Modified` response.
+pub fn touch(uri: &str, headers: &HeaderMap) {
+    Lazy::force(&DB_INIT);
+    if let Some(v) = expires(headers) {
+        db_touch(uri, v);
+    }
+}
+
+static TICKER: Lazy<Ticker> = Lazy::new(|| Ticker::new_p8(&[(3, 30, 0)]));
+pub async fn tick() {
+    if TICKER.tick() {
+        tokio::task::spawn_blocking(|| {
+            Lazy::force(&DB_INIT);
+            db_clean();
+        })
+        .await
+        .unwrap();
+    }
+}
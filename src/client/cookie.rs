@@ -0,0 +1,245 @@
+//! Cookie jar, follows the storage model of RFC 6265 section 5.3.
+//!
+//! The public suffix list is not checked, only use with trusted sites.
+use hyper::header::{HeaderMap, HeaderValue, SET_COOKIE};
+use hyper::Uri;
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Clone, Debug)]
+struct Cookie {
+    name: String,
+    value: String,
+    domain: String,
+    /// Without `Domain` attribute, only send to the exact host.
+    host_only: bool,
+    path: String,
+    secure: bool,
+    /// `None` means session cookie.
+    expires: Option<SystemTime>,
+}
+
+impl Cookie {
+    fn expired(&self, now: SystemTime) -> bool {
+        matches!(self.expires, Some(v) if v <= now)
+    }
+}
+
+fn domain_match(host: &str, domain: &str) -> bool {
+    match host.strip_suffix(domain) {
+        Some("") => true,
+        Some(v) => v.ends_with('.') && host.parse::<IpAddr>().is_err(),
+        None => false,
+    }
+}
+
+fn path_match(path: &str, cookie_path: &str) -> bool {
+    match path.strip_prefix(cookie_path) {
+        Some(v) => v.is_empty() || cookie_path.ends_with('/') || v.starts_with('/'),
+        None => false,
+    }
+}
+
+/// The directory of request path, like `/a/b` for `/a/b/c`.
+fn default_path(path: &str) -> &str {
+    match path.rfind('/') {
+        Some(0) | None => "/",
+        Some(i) => &path[..i],
+    }
+}
+
+/// Parse a `Set-Cookie` header value, returns `None` if invalid or rejected.
+fn parse(line: &str, host: &str, path: &str) -> Option<Cookie> {
+    let mut parts = line.split(';');
+    let (name, value) = parts.next()?.split_once('=')?;
+    let name = name.trim();
+    if name.is_empty() {
+        return None;
+    }
+    let mut cookie = Cookie {
+        name: name.to_string(),
+        value: value.trim().to_string(),
+        domain: host.to_string(),
+        host_only: true,
+        path: default_path(path).to_string(),
+        secure: false,
+        expires: None,
+    };
+    let mut max_age = None;
+    for attr in parts {
+        let (k, v) = attr.split_once('=').unwrap_or((attr, ""));
+        let v = v.trim();
+        match k.trim().to_ascii_lowercase().as_str() {
+            "domain" if !v.is_empty() => {
+                let domain = v.trim_start_matches('.').to_ascii_lowercase();
+                if !domain_match(host, &domain) {
+                    return None; // set cookie for other sites
+                }
+                cookie.domain = domain;
+                cookie.host_only = false;
+            }
+            "path" if v.starts_with('/') => cookie.path = v.to_string(),
+            "secure" => cookie.secure = true,
+            "max-age" => max_age = v.parse::<i64>().ok(),
+            "expires" => cookie.expires = httpdate::parse_http_date(v).ok().or(cookie.expires),
+            _ => {}
+        }
+    }
+    // the Max-Age has precedence over Expires
+    if let Some(secs) = max_age {
+        cookie.expires = Some(match secs {
+            ..=0 => UNIX_EPOCH,
+            _ => SystemTime::now() + Duration::from_secs(secs as _),
+        });
+    }
+    Some(cookie)
+}
+
+#[derive(Default)]
+pub struct CookieJar {
+    cookies: Vec<Cookie>,
+}
+
+impl CookieJar {
+    /// Store cookies from the `Set-Cookie` headers of a response.
+    pub fn store(&mut self, uri: &Uri, headers: &HeaderMap) {
+        let host = uri.host().unwrap_or_default().to_ascii_lowercase();
+        let now = SystemTime::now();
+        for line in headers.get_all(SET_COOKIE) {
+            let cookie = match line.to_str().ok().and_then(|v| parse(v, &host, uri.path())) {
+                Some(v) => v,
+                None => continue,
+            };
+            // replace the old one, and an expired cookie means deletion
+            self.cookies.retain(|v| {
+                (&v.name, &v.domain, &v.path) != (&cookie.name, &cookie.domain, &cookie.path)
+            });
+            if !cookie.expired(now) {
+                self.cookies.push(cookie);
+            }
+        }
+    }
+
+    /// The `Cookie` header value for a request, `None` if no cookie matches.
+    pub fn header(&mut self, uri: &Uri) -> Option<HeaderValue> {
+        let host = uri.host().unwrap_or_default().to_ascii_lowercase();
+        let path = match uri.path() {
+            "" => "/",
+            v => v,
+        };
+        let https = uri.scheme_str() == Some("https");
+        let now = SystemTime::now();
+        self.cookies.retain(|v| !v.expired(now));
+        let mut matched = { self.cookies.iter() }
+            .filter(|v| match v.host_only {
+                true => host == v.domain,
+                false => domain_match(&host, &v.domain),
+            })
+            .filter(|v| path_match(path, &v.path) && (https || !v.secure))
+            .collect::<Vec<_>>();
+        if matched.is_empty() {
+            return None;
+        }
+        // longer paths first, the stable sort keeps the creation order of others
+        matched.sort_by_key(|v| std::cmp::Reverse(v.path.len()));
+        let pairs = matched.iter().map(|v| format!("{}={}", v.name, v.value));
+        HeaderValue::from_str(&pairs.collect::<Vec<_>>().join("; ")).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store(jar: &mut CookieJar, uri: &str, lines: &[&str]) {
+        let mut headers = HeaderMap::new();
+        for v in lines {
+            headers.append(SET_COOKIE, HeaderValue::from_str(v).unwrap());
+        }
+        jar.store(&uri.parse().unwrap(), &headers);
+    }
+
+    fn header(jar: &mut CookieJar, uri: &str) -> Option<String> {
+        let v = jar.header(&uri.parse().unwrap())?;
+        Some(v.to_str().unwrap().to_string())
+    }
+
+    #[test]
+    fn domain() {
+        let mut jar = CookieJar::default();
+        let lines = ["a=1", "b=2; Domain=.Example.com", "c=3; Domain=other.com"];
+        store(&mut jar, "http://a.example.com/", &lines);
+        assert_eq!(
+            header(&mut jar, "http://a.example.com/").unwrap(),
+            "a=1; b=2"
+        );
+        assert_eq!(header(&mut jar, "http://x.a.example.com/").unwrap(), "b=2");
+        assert_eq!(header(&mut jar, "http://example.com/").unwrap(), "b=2");
+        assert_eq!(header(&mut jar, "http://badexample.com/"), None);
+        assert_eq!(header(&mut jar, "http://other.com/"), None);
+
+        // IP hosts match only themselves
+        assert!(!domain_match("1.2.3.4", "2.3.4"));
+        assert!(domain_match("1.2.3.4", "1.2.3.4"));
+        store(&mut jar, "http://1.2.3.4/", &["d=4; Domain=2.3.4"]);
+        assert_eq!(header(&mut jar, "http://1.2.3.4/"), None);
+    }
+
+    #[test]
+    fn path() {
+        assert!(path_match("/a/b", "/a/b"));
+        assert!(path_match("/a/b/c", "/a/b"));
+        assert!(path_match("/a/b/c", "/a/"));
+        assert!(!path_match("/a/bc", "/a/b"));
+        assert!(!path_match("/a", "/a/b"));
+        assert_eq!(default_path("/a/b/c"), "/a/b");
+        assert_eq!(default_path("/a"), "/");
+        assert_eq!(default_path(""), "/");
+
+        // longer paths first
+        let mut jar = CookieJar::default();
+        store(
+            &mut jar,
+            "http://a.com/x/y/z",
+            &["a=1; Path=/", "b=2", "c=3; Path=/x"],
+        );
+        assert_eq!(
+            header(&mut jar, "http://a.com/x/y/z").unwrap(),
+            "b=2; c=3; a=1"
+        );
+        assert_eq!(header(&mut jar, "http://a.com/x").unwrap(), "c=3; a=1");
+        assert_eq!(header(&mut jar, "http://a.com/").unwrap(), "a=1");
+    }
+
+    #[test]
+    fn expires() {
+        let past = "Expires=Thu, 01 Jan 1970 00:00:01 GMT";
+        let future = "Expires=Fri, 01 Jan 2100 00:00:00 GMT";
+        let now = SystemTime::now();
+        let cookie = parse(&format!("a=1; {past}; Max-Age=60"), "a.com", "/").unwrap();
+        assert!(!cookie.expired(now));
+        let cookie = parse(&format!("a=1; Max-Age=60; {past}"), "a.com", "/").unwrap();
+        assert!(!cookie.expired(now));
+        let cookie = parse(&format!("a=1; {future}; Max-Age=-1"), "a.com", "/").unwrap();
+        assert!(cookie.expired(now));
+        let cookie = parse(&format!("a=1; {future}"), "a.com", "/").unwrap();
+        assert!(!cookie.expired(now));
+        assert_eq!(parse("a=1", "a.com", "/").unwrap().expires, None);
+
+        // deletion
+        let mut jar = CookieJar::default();
+        store(&mut jar, "http://a.com/", &["a=1", "b=2"]);
+        store(&mut jar, "http://a.com/", &["a=; Max-Age=0"]);
+        assert_eq!(header(&mut jar, "http://a.com/").unwrap(), "b=2");
+        store(&mut jar, "http://a.com/", &[&format!("b=; {past}")]);
+        assert_eq!(header(&mut jar, "http://a.com/"), None);
+    }
+
+    #[test]
+    fn secure() {
+        let mut jar = CookieJar::default();
+        store(&mut jar, "https://a.com/", &["a=1; Secure", "b=2"]);
+        assert_eq!(header(&mut jar, "https://a.com/").unwrap(), "a=1; b=2");
+        assert_eq!(header(&mut jar, "http://a.com/").unwrap(), "b=2");
+    }
+}
@@ -0,0 +1,866 @@
+//! HTTP client, shared by all units.
+//!
+//! Use `fetch_text` and `fetch_json` for simple cases, or `Fetch` builder for options.
+//! Allow both HTTPS and HTTP. Unlike `reqwest` crate, redirects are not followed by default.
+mod body;
+pub mod breaker;
+pub mod cache;
+mod cookie;
+pub mod proxy;
+mod session;
+use crate::care;
+use crate::utils::encode_uri;
+use anyhow::Result;
+pub use body::{read_body, read_text, BodyError, BodyStream, DEFAULT_BODY_LIMIT};
+use cookie::CookieJar;
+use hyper::body::Bytes;
+use hyper::client::connect::HttpConnector;
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+use hyper::header::{ACCEPT_ENCODING, AUTHORIZATION, CONTENT_ENCODING, CONTENT_LENGTH};
+use hyper::header::{CONTENT_TYPE, COOKIE, LOCATION, USER_AGENT};
+use hyper::http::request::Parts;
+use hyper::service::Service;
+use hyper::{Body, Client, Method, Request, Response, StatusCode, Uri};
+use hyper_rustls::HttpsConnector;
+use once_cell::sync::Lazy;
+use proxy::Proxy;
+pub use session::Session;
+use std::collections::HashMap;
+use std::future::Future;
+use std::io::Read;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::{ClientConfig, OwnedTrustAnchor, RootCertStore};
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+tokio::task_local! {
+    /// Set by `Fetch`, read by `Connector` while connecting.
+    static CONNECT_TIMEOUT: Duration;
+}
+
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(250);
+const DEFAULT_USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+/// The connector with per request connect timeout and proxy.
+#[derive(Clone)]
+pub struct Connector {
+    http: HttpConnector,
+    proxy: Option<Proxy>,
+}
+
+impl Service<Uri> for Connector {
+    type Response = TcpStream;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.http.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        // hyper may continue connecting in background, out of the task local scope
+        let timeout = CONNECT_TIMEOUT.try_with(|v| *v);
+        let timeout = timeout.unwrap_or(DEFAULT_CONNECT_TIMEOUT);
+        let host = uri.host().unwrap_or_default();
+        let fut: Self::Future = match self.proxy.clone().filter(|_| !proxy::bypass(host)) {
+            Some(proxy) => Box::pin(async move {
+                let https = uri.scheme_str() == Some("https");
+                let port = uri.port_u16().unwrap_or(if https { 443 } else { 80 });
+                let mut stream = TcpStream::connect(proxy.addr()).await?;
+                stream.set_nodelay(true)?;
+                proxy.tunnel(&mut stream, uri.host().unwrap(), port).await?;
+                Ok(stream)
+            }),
+            None => {
+                let fut = self.http.call(uri);
+                Box::pin(async move { Ok(fut.await?) })
+            }
+        };
+        Box::pin(async move {
+            match tokio::time::timeout(timeout, fut).await {
+                Ok(v) => v,
+                Err(e) => Err(e.into()),
+            }
+        })
+    }
+}
+
+static TLS_CFG: Lazy<ClientConfig> = Lazy::new(|| {
+    // https://github.com/seanmonstar/reqwest/blob/v0.11.11/src/async_impl/client.rs#L340
+    let root_cert_store = RootCertStore {
+        roots: { webpki_roots::TLS_SERVER_ROOTS.0.iter() }
+            .map(|trust_anchor| {
+                OwnedTrustAnchor::from_subject_spki_name_constraints(
+                    trust_anchor.subject,
+                    trust_anchor.spki,
+                    trust_anchor.name_constraints,
+                )
+            })
+            .collect(),
+    };
+    let tls_cfg = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(root_cert_store)
+        .with_no_client_auth();
+    // tls_cfg.alpn_protocols = vec![b"http/1.1".to_vec()]; // http2 is not supported
+    tls_cfg
+});
+
+type HttpsClient = Client<HttpsConnector<Connector>>;
+
+/// Get the client of a proxy, connections are pooled separately for each proxy.
+fn client(proxy: Option<Proxy>) -> HttpsClient {
+    static CLIENTS: Lazy<Mutex<HashMap<Option<Proxy>, HttpsClient>>> = Lazy::new(Default::default);
+    let mut clients = CLIENTS.lock().unwrap();
+    let client = clients.entry(proxy.clone()).or_insert_with(|| {
+        let mut http = HttpConnector::new();
+        http.enforce_http(false); // allow HTTPS
+        let connector = HttpsConnector::from((Connector { http, proxy }, TLS_CFG.clone()));
+        Client::builder().build(connector)
+    });
+    client.clone()
+}
+
+pub trait ToRequest {
+    fn into_request(self) -> Request<Body>;
+}
+impl ToRequest for Request<Body> {
+    fn into_request(self) -> Request<Body> {
+        self
+    }
+}
+impl ToRequest for &str {
+    fn into_request(self) -> Request<Body> {
+        let ret = Request::get(encode_uri(self)).body(Body::empty()).unwrap();
+        ret.into_request()
+    }
+}
+impl ToRequest for &String {
+    fn into_request(self) -> Request<Body> {
+        self.as_str().into_request()
+    }
+}
+
+#[derive(Clone)]
+struct Options {
+    max_redirects: u32,
+    connect_timeout: Duration,
+    timeout: Option<Duration>,
+    decompress: bool,
+    headers: HeaderMap,
+    unit: Option<&'static str>,
+    retries: u32,
+    idempotent: Option<bool>,
+    body_limit: usize,
+    jar: Option<Arc<Mutex<CookieJar>>>,
+    cache: bool,
+}
+
+/// Request builder with options.
+///
+/// # Example
+///
+/// ```
+/// let text = Fetch::new("https://example.com")
+///     .max_redirects(5)
+///     .timeout(Duration::from_secs(3))
+///     .header(ACCEPT, HeaderValue::from_static("text/html"))
+///     .text()
+///     .await?;
+/// ```
+pub struct Fetch {
+    request: Request<Body>,
+    opts: Options,
+}
+
+impl Fetch {
+    /// Create with defaults: no redirect, no retry, 10s connect timeout, no total timeout, decompress.
+    ///
+    /// Sends `User-Agent: ksite/<version>` if the request has not, override it by `header`.
+    pub fn new(request: impl ToRequest) -> Self {
+        let mut headers = HeaderMap::new();
+        headers.insert(USER_AGENT, HeaderValue::from_static(DEFAULT_USER_AGENT));
+        Fetch {
+            request: request.into_request(),
+            opts: Options {
+                max_redirects: 0,
+                connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+                timeout: None,
+                decompress: true,
+                headers,
+                unit: None,
+                retries: 0,
+                idempotent: None,
+                body_limit: DEFAULT_BODY_LIMIT,
+                jar: None,
+                cache: false,
+            },
+        }
+    }
+
+    /// Follow redirects at most `v` times, `0` means returns the redirect response directly.
+    pub fn max_redirects(mut self, v: u32) -> Self {
+        self.opts.max_redirects = v;
+        self
+    }
+
+    pub fn connect_timeout(mut self, v: Duration) -> Self {
+        self.opts.connect_timeout = v;
+        self
+    }
+
+    /// The total timeout, includes redirects and reading body in `text()` or `json()`.
+    ///
+    /// The error can be detected by `e.is::<tokio::time::error::Elapsed>()`.
+    pub fn timeout(mut self, v: Duration) -> Self {
+        self.opts.timeout = Some(v);
+        self
+    }
+
+    /// Decode gzip and deflate response body, enabled by default.
+    pub fn decompress(mut self, v: bool) -> Self {
+        self.opts.decompress = v;
+        self
+    }
+
+    /// Default header, only used if the request does not have it, such as `User-Agent`.
+    pub fn header(mut self, k: HeaderName, v: HeaderValue) -> Self {
+        self.opts.headers.insert(k, v);
+        self
+    }
+
+    /// Retry at most `v` times if failed with network error, 5xx or 429, only for idempotent methods
+    /// by default.
+    ///
+    /// The delay is random between zero and `250ms * 2^attempt`.
+    pub fn retry(mut self, v: u32) -> Self {
+        self.opts.retries = v;
+        self
+    }
+
+    /// Mark the request as idempotent or not, to override the guess from method.
+    ///
+    /// For example, a login POST request may be safe to retry.
+    pub fn idempotent(mut self, v: bool) -> Self {
+        self.opts.idempotent = Some(v);
+        self
+    }
+
+    /// Limit the size of response body, in bytes, defaults to `DEFAULT_BODY_LIMIT`.
+    ///
+    /// Applies to the decompressed body, and to `text()`, `json()` and `stream()`.
+    pub fn body_limit(mut self, v: usize) -> Self {
+        self.opts.body_limit = v;
+        self
+    }
+
+    /// Use the HTTP cache for `GET` requests, see `client::cache`.
+    ///
+    /// The stale content is served if upstream failed, with a `Warning` header. Ignored for the
+    /// requests with credentials, which are `Cookie`, `Authorization` or a `Session`.
+    pub fn cache(mut self, v: bool) -> Self {
+        self.opts.cache = v;
+        self
+    }
+
+    /// Use the proxy config of the unit, see `client::proxy`.
+    pub fn unit(mut self, v: &'static str) -> Self {
+        self.opts.unit = Some(v);
+        self
+    }
+
+    async fn with_timeout<T>(
+        timeout: Option<Duration>,
+        fut: impl Future<Output = Result<T>>,
+    ) -> Result<T> {
+        match timeout {
+            Some(v) => tokio::time::timeout(v, fut).await?,
+            None => fut.await,
+        }
+    }
+
+    /// Send to a single URI, retry if failed and allowed, guarded by the circuit breaker.
+    async fn send_retry(
+        client: &HttpsClient,
+        opts: &Options,
+        parts: &Parts,
+        body: &Bytes,
+    ) -> Result<Response<Body>> {
+        let host = parts.uri.host().unwrap_or_default();
+        let idempotent = opts
+            .idempotent
+            .unwrap_or_else(|| parts.method.is_idempotent());
+        let retries = if idempotent { opts.retries } else { 0 };
+        let mut attempt = 0;
+        loop {
+            breaker::check(host)?;
+            let mut request = Request::new(Body::from(body.clone()));
+            *request.method_mut() = parts.method.clone();
+            *request.uri_mut() = parts.uri.clone();
+            *request.headers_mut() = parts.headers.clone();
+            if let Some(jar) = &opts.jar {
+                if let Some(v) = jar.lock().unwrap().header(&parts.uri) {
+                    let headers = request.headers_mut();
+                    let v = match headers.get(COOKIE) {
+                        Some(old) => HeaderValue::from_bytes(
+                            &[old.as_bytes(), b"; ", v.as_bytes()].concat(),
+                        )?,
+                        None => v,
+                    };
+                    headers.insert(COOKIE, v);
+                }
+            }
+            let fut = client.request(request);
+            let result = CONNECT_TIMEOUT.scope(opts.connect_timeout, fut).await;
+            if let (Some(jar), Ok(response)) = (&opts.jar, &result) {
+                jar.lock().unwrap().store(&parts.uri, response.headers());
+            }
+            let status = result.as_ref().map(|v| v.status());
+            // the upstream is alive if responded 429, but it's worth to retry
+            breaker::record(host, matches!(status, Ok(v) if !v.is_server_error()));
+            let retryable = match status {
+                Ok(v) => v.is_server_error() || v == StatusCode::TOO_MANY_REQUESTS,
+                Err(_) => true,
+            };
+            if !retryable || attempt == retries {
+                return Ok(result?);
+            }
+            attempt += 1;
+            // exponential backoff with full jitter
+            let max = RETRY_BASE_DELAY * 2u32.pow(attempt.min(6));
+            tokio::time::sleep(max.mul_f64(rand::random())).await;
+        }
+    }
+
+    async fn send_inner(self) -> Result<Response<Body>> {
+        let Fetch { mut request, opts } = self;
+        for (k, v) in &opts.headers {
+            if !request.headers().contains_key(k) {
+                request.headers_mut().insert(k, v.clone());
+            }
+        }
+        if opts.decompress && !request.headers().contains_key(ACCEPT_ENCODING) {
+            let v = HeaderValue::from_static("gzip, deflate");
+            request.headers_mut().insert(ACCEPT_ENCODING, v);
+        }
+
+        let client = client(proxy::select(opts.unit));
+        // buffer the body to send again while retrying or redirecting
+        let (mut parts, body) = request.into_parts();
+        let mut body = hyper::body::to_bytes(body).await?;
+        let mut hops = Vec::new();
+        let mut response = loop {
+            let response = Self::send_retry(&client, &opts, &parts, &body).await?;
+            let location = match response.headers().get(LOCATION) {
+                Some(v) if response.status().is_redirection() => v.to_str()?,
+                _ => break response,
+            };
+            if hops.len() == opts.max_redirects as usize {
+                break response;
+            }
+            let uri = resolve_location(&parts.uri, location)?;
+            hops.push(uri.clone());
+            if uri.authority() != parts.uri.authority() {
+                parts.headers.remove(AUTHORIZATION);
+                parts.headers.remove(COOKIE);
+            }
+            parts.uri = uri;
+            if response.status() == StatusCode::SEE_OTHER
+                || matches!(response.status().as_u16(), 301 | 302) && parts.method == Method::POST
+            {
+                parts.method = Method::GET;
+                parts.headers.remove(CONTENT_TYPE);
+                parts.headers.remove(CONTENT_LENGTH);
+                body = Bytes::new();
+            }
+        };
+
+        if !hops.is_empty() {
+            response.extensions_mut().insert(Redirects(hops));
+        }
+
+        if opts.decompress {
+            let encoding = response.headers().get(CONTENT_ENCODING);
+            let encoding = encoding.map(|v| v.as_bytes().to_ascii_lowercase());
+            if let Some(b"gzip" | b"deflate") = encoding.as_deref() {
+                let limit = opts.body_limit;
+                let raw = read_body(std::mem::take(response.body_mut()), limit).await?;
+                let mut buf = Vec::new();
+                // read one more byte to detect exceeding, avoid decompression bomb
+                let take = limit as u64 + 1;
+                if encoding.as_deref() == Some(b"gzip") {
+                    flate2::read::GzDecoder::new(&raw[..])
+                        .take(take)
+                        .read_to_end(&mut buf)?;
+                } else if flate2::read::ZlibDecoder::new(&raw[..])
+                    .take(take)
+                    .read_to_end(&mut buf)
+                    .is_err()
+                {
+                    // some servers send raw deflate stream without zlib wrapper
+                    buf.clear();
+                    let decoder = flate2::read::DeflateDecoder::new(&raw[..]);
+                    decoder.take(take).read_to_end(&mut buf)?;
+                }
+                if buf.len() > limit {
+                    return Err(BodyError::TooLarge(limit).into());
+                }
+                response.headers_mut().remove(CONTENT_ENCODING);
+                response
+                    .headers_mut()
+                    .insert(CONTENT_LENGTH, buf.len().into());
+                *response.body_mut() = Body::from(buf);
+            }
+        }
+        Ok(response)
+    }
+
+    /// The cache is shared by all callers, so not for the personalized content.
+    fn cacheable(&self) -> bool {
+        let credential = |v: &HeaderMap| v.contains_key(COOKIE) || v.contains_key(AUTHORIZATION);
+        self.opts.cache
+            && self.request.method() == Method::GET
+            && self.opts.jar.is_none()
+            && !credential(self.request.headers())
+            && !credential(&self.opts.headers)
+    }
+
+    /// Send the request and return the response.
+    pub async fn send(self) -> Result<Response<Body>> {
+        if self.cacheable() {
+            return self.send_cached().await;
+        }
+        Self::with_timeout(self.opts.timeout, self.send_inner()).await
+    }
+
+    async fn send_cached(mut self) -> Result<Response<Body>> {
+        let uri = self.request.uri().to_string();
+        let cached = cache::get(&uri);
+        match &cached {
+            Some(entry) if entry.is_fresh() => return Ok(cached.unwrap().into_response(false)),
+            Some(entry) => {
+                for (k, v) in entry.conditions() {
+                    self.request.headers_mut().entry(k).or_insert(v);
+                }
+            }
+            None => {}
+        }
+        let limit = self.opts.body_limit;
+        let result = Self::with_timeout(self.opts.timeout, async {
+            let response = self.send_inner().await?;
+            if response.status() != StatusCode::OK {
+                return Ok(response);
+            }
+            let (parts, body) = response.into_parts();
+            let body = read_body(body, limit).await?;
+            cache::store(&uri, &parts.headers, &body);
+            Ok(Response::from_parts(parts, Body::from(body)))
+        })
+        .await;
+        match (result, cached) {
+            (Ok(response), Some(entry)) if response.status() == StatusCode::NOT_MODIFIED => {
+                cache::touch(&uri, response.headers());
+                Ok(entry.into_response(false))
+            }
+            (Ok(response), Some(entry)) if response.status().is_server_error() => {
+                Ok(entry.into_response(true))
+            }
+            (Err(e), Some(entry)) => {
+                care!(Err::<(), _>(e)).ok();
+                Ok(entry.into_response(true))
+            }
+            (result, _) => result,
+        }
+    }
+
+    /// Send the request and return the body in chunks, for large downloads.
+    ///
+    /// The total timeout does not cover reading the chunks.
+    pub async fn stream(self) -> Result<BodyStream> {
+        let limit = self.opts.body_limit;
+        let response = self.send().await?;
+        Ok(BodyStream::new(response.into_body(), limit))
+    }
+
+    /// Send the request and return the body as text.
+    pub async fn text(self) -> Result<String> {
+        // the cached body is in memory, the timeout only matters for revalidating
+        let timeout = self.opts.timeout.filter(|_| !self.cacheable());
+        Self::with_timeout(timeout, async { Ok(self.stream().await?.text().await?) }).await
+    }
+
+    /// Send the request and get field by pointer, the value is converted to string.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let v = Fetch::new("https://api.io").json("/data/size").await;
+    /// // is this? { "data": { "size": "1024" } }
+    /// // or this? { "data": { "size": 1024 } }
+    /// assert_eq!(v, Ok("1024".to_string())); // the same result!
+    /// ```
+    pub async fn json(self, pointer: &str) -> Result<String> {
+        json_pointer(&self.text().await?, pointer)
+    }
+}
+
+// The helpers below are kept for the call sites of the old `utils::fetch`, units use `Fetch` with
+// their own options instead.
+
+/// Send a request with the defaults of `Fetch::new`, the redirect is not followed.
+#[allow(unused)]
+pub async fn fetch(request: impl ToRequest) -> Result<Response<Body>> {
+    Fetch::new(request).send().await
+}
+
+/// Fetch a URI, returns as text.
+#[allow(unused)]
+pub async fn fetch_text(request: impl ToRequest) -> Result<String> {
+    let response = fetch(request).await?;
+    Ok(read_text(response.into_body(), DEFAULT_BODY_LIMIT).await?)
+}
+
+/// Fetch a URI which response json, get field by pointer, see `Fetch::json`.
+#[allow(unused)]
+pub async fn fetch_json(request: impl ToRequest, pointer: &str) -> Result<String> {
+    Fetch::new(request).json(pointer).await
+}
+
+/// Get field by pointer from JSON text, the value is converted to string like `Fetch::json`.
+pub fn json_pointer(text: &str, pointer: &str) -> Result<String> {
+    let v = serde_json::from_str::<serde_json::Value>(text)?;
+    let v = v
+        .pointer(pointer)
+        .ok_or_else(|| anyhow::anyhow!("json field not found"))?
+        .to_string();
+    Ok(v.trim_matches('"').to_owned())
+}
+
+/// The URIs of followed redirects in order, inserted into response extensions by `Fetch`.
+#[derive(Clone, Debug)]
+pub struct Redirects(pub Vec<Uri>);
+
+impl Redirects {
+    /// Find a query parameter in the URIs, the latest first.
+    pub fn query(&self, name: &str) -> Option<&str> {
+        let queries = self.0.iter().rev().filter_map(|v| v.query());
+        let mut pairs = queries.flat_map(|v| v.split('&'));
+        pairs.find_map(|v| v.strip_prefix(name)?.strip_prefix('='))
+    }
+}
+
+/// Resolve the `Location` header value, relative to the current URI.
+fn resolve_location(base: &Uri, location: &str) -> Result<Uri> {
+    // the fragment is never sent
+    let location = location.split('#').next().unwrap();
+    let scheme = base.scheme_str().unwrap_or("http");
+    let authority = base.authority().map_or("", |v| v.as_str());
+    let location = if location.contains("://") {
+        location.to_string()
+    } else if let Some(v) = location.strip_prefix("//") {
+        format!("{scheme}://{v}")
+    } else if location.starts_with('/') {
+        format!("{scheme}://{authority}{location}")
+    } else if location.starts_with('?') {
+        format!("{scheme}://{authority}{}{location}", base.path())
+    } else {
+        let dir = base.path().rsplit_once('/').map_or("", |v| v.0);
+        format!("{scheme}://{authority}{dir}/{location}")
+    };
+    Ok(match Uri::try_from(&location) {
+        Ok(v) => v,
+        Err(_) => Uri::try_from(encode_uri(&location))?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::header::{HeaderMap as AxumHeaderMap, CONTENT_ENCODING as ENCODING};
+    use axum::response::{IntoResponse, Redirect};
+    use axum::routing::{get, post, Router};
+    use flate2::write::{DeflateEncoder, GzEncoder};
+    use flate2::Compression;
+    use futures_core::Stream;
+    use hyper::header::{CACHE_CONTROL, ETAG, IF_NONE_MATCH, SET_COOKIE, WARNING};
+    use std::future::poll_fn;
+    use std::io::Write;
+    use std::net::SocketAddr;
+    use std::pin::Pin;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn location() {
+        let base = Uri::from_static("https://a.com/b/c?d=1");
+        let resolve = |v| resolve_location(&base, v).unwrap().to_string();
+        assert_eq!(resolve("http://x.com/y"), "http://x.com/y");
+        assert_eq!(resolve("//x.com/y"), "https://x.com/y");
+        assert_eq!(resolve("/y?z=1#f"), "https://a.com/y?z=1");
+        assert_eq!(resolve("?a=1"), "https://a.com/b/c?a=1");
+        assert_eq!(resolve("e"), "https://a.com/b/e");
+        assert_eq!(resolve("/中"), "https://a.com/%e4%b8%ad");
+    }
+
+    fn compress(mut encoder: impl Write, data: &[u8]) {
+        encoder.write_all(data).unwrap();
+    }
+
+    // requests counters of the cache tests
+    static FRESH: AtomicUsize = AtomicUsize::new(0);
+    static NOT_MODIFIED: AtomicUsize = AtomicUsize::new(0);
+    static FLAKY: AtomicUsize = AtomicUsize::new(0);
+    static NO_STORE: AtomicUsize = AtomicUsize::new(0);
+
+    /// A local server, returns the base URI.
+    fn server() -> String {
+        let app = Router::new()
+            .route("/text", get(|| async { "ok" }))
+            .route("/to-query", get(|| async { Redirect::to("?a=1") }))
+            .route("/to-text", get(|| async { Redirect::to("/text") }))
+            .route("/post", post(|| async { Redirect::to("/method") }))
+            .route("/method", get(|| async { "get" }).post(|| async { "post" }))
+            .route(
+                "/slow",
+                get(|| async {
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                    "slow"
+                }),
+            )
+            .route(
+                "/gzip",
+                get(|| async {
+                    let mut buf = Vec::new();
+                    compress(GzEncoder::new(&mut buf, Compression::default()), b"gzip ok");
+                    ([(ENCODING, "gzip")], buf)
+                }),
+            )
+            .route(
+                "/deflate",
+                get(|| async {
+                    let mut buf = Vec::new();
+                    compress(
+                        DeflateEncoder::new(&mut buf, Compression::default()),
+                        b"raw ok",
+                    );
+                    ([(ENCODING, "deflate")], buf)
+                }),
+            )
+            .route(
+                "/bomb",
+                get(|| async {
+                    let mut buf = Vec::new();
+                    let data = vec![0; DEFAULT_BODY_LIMIT + 1];
+                    compress(GzEncoder::new(&mut buf, Compression::default()), &data);
+                    ([(ENCODING, "gzip")], buf)
+                }),
+            )
+            .route(
+                "/login",
+                get(|| async { ([(SET_COOKIE, "sid=1; Path=/")], Redirect::to("/cookie")) }),
+            )
+            .route(
+                "/cookie",
+                get(|headers: AxumHeaderMap| async move {
+                    let v = headers.get(COOKIE).map(|v| v.to_str().unwrap());
+                    v.unwrap_or_default().to_string()
+                }),
+            )
+            .route(
+                "/fresh",
+                get(|| async {
+                    let n = FRESH.fetch_add(1, Ordering::Relaxed);
+                    ([(CACHE_CONTROL, "max-age=60")], n.to_string())
+                }),
+            )
+            .route(
+                "/etag",
+                get(|headers: AxumHeaderMap| async move {
+                    if headers.get(IF_NONE_MATCH).is_some_and(|v| v == "\"v1\"") {
+                        NOT_MODIFIED.fetch_add(1, Ordering::Relaxed);
+                        return StatusCode::NOT_MODIFIED.into_response();
+                    }
+                    let headers = [(CACHE_CONTROL, "no-cache"), (ETAG, "\"v1\"")];
+                    (headers, "etag").into_response()
+                }),
+            )
+            .route(
+                "/flaky",
+                get(|| async {
+                    match FLAKY.fetch_add(1, Ordering::Relaxed) {
+                        0 => ([(CACHE_CONTROL, "no-cache")], "flaky").into_response(),
+                        _ => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+                    }
+                }),
+            )
+            .route(
+                "/no-store",
+                get(|| async {
+                    let n = NO_STORE.fetch_add(1, Ordering::Relaxed);
+                    ([(CACHE_CONTROL, "no-store")], n.to_string())
+                }),
+            )
+            .route(
+                "/private",
+                get(|| async { ([(CACHE_CONTROL, "private, max-age=60")], "private") }),
+            )
+            .route(
+                "/chunks",
+                get(|| async {
+                    let (mut tx, body) = Body::channel();
+                    tokio::spawn(async move {
+                        for _ in 0..4 {
+                            tx.send_data(Bytes::from_static(b"abcd")).await.unwrap();
+                            tokio::time::sleep(Duration::from_millis(50)).await;
+                        }
+                    });
+                    Response::new(body)
+                }),
+            )
+            .route(
+                "/encoding",
+                get(|headers: AxumHeaderMap| async move {
+                    let v = headers.get(ACCEPT_ENCODING).map(|v| v.to_str().unwrap());
+                    v.unwrap_or_default().to_string()
+                }),
+            );
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr: SocketAddr = listener.local_addr().unwrap();
+        let server = axum::Server::from_tcp(listener).unwrap();
+        tokio::spawn(server.serve(app.into_make_service()));
+        format!("http://{addr}")
+    }
+
+    #[tokio::test]
+    async fn redirects() {
+        let base = server();
+        let uri = format!("{base}/to-query");
+        let r = Fetch::new(&uri).max_redirects(1).send().await.unwrap();
+        assert_eq!(r.status(), StatusCode::SEE_OTHER); // `/to-query?a=1` redirects again
+        let hops = r.extensions().get::<Redirects>().unwrap();
+        assert_eq!(hops.0[0].to_string(), format!("{base}/to-query?a=1"));
+        assert_eq!(hops.query("a"), Some("1"));
+
+        // not followed by default
+        let uri = format!("{base}/to-text");
+        let r = Fetch::new(&uri).send().await.unwrap();
+        assert_eq!(r.status(), StatusCode::SEE_OTHER);
+        assert!(r.extensions().get::<Redirects>().is_none());
+        let text = Fetch::new(&uri).max_redirects(1).text().await.unwrap();
+        assert_eq!(text, "ok");
+
+        // 303 changes POST to GET
+        let request = Request::post(format!("{base}/post"))
+            .body(Body::from("x"))
+            .unwrap();
+        let text = Fetch::new(request).max_redirects(1).text().await.unwrap();
+        assert_eq!(text, "get");
+    }
+
+    #[tokio::test]
+    async fn timeout() {
+        let base = server();
+        let fetch = Fetch::new(&format!("{base}/slow")).timeout(Duration::from_millis(100));
+        let e = fetch.text().await.unwrap_err();
+        assert!(e.is::<tokio::time::error::Elapsed>());
+        let fetch = Fetch::new(&format!("{base}/text")).timeout(Duration::from_secs(5));
+        assert_eq!(fetch.text().await.unwrap(), "ok");
+    }
+
+    #[tokio::test]
+    async fn decompress() {
+        let base = server();
+        let text = fetch_text(&format!("{base}/gzip")).await.unwrap();
+        assert_eq!(text, "gzip ok");
+        let text = fetch_text(&format!("{base}/deflate")).await.unwrap();
+        assert_eq!(text, "raw ok");
+        let e = fetch_text(&format!("{base}/bomb")).await.unwrap_err();
+        assert!(matches!(e.downcast_ref(), Some(BodyError::TooLarge(_))));
+        let text = fetch_text(&format!("{base}/encoding")).await.unwrap();
+        assert_eq!(text, "gzip, deflate");
+
+        // the raw body if disabled
+        let fetch = Fetch::new(&format!("{base}/gzip")).decompress(false);
+        let r = fetch.send().await.unwrap();
+        assert_eq!(r.headers()[CONTENT_ENCODING], "gzip");
+        let text = Fetch::new(&format!("{base}/encoding")).decompress(false);
+        assert_eq!(text.text().await.unwrap(), "");
+    }
+
+    #[tokio::test]
+    async fn stream() {
+        async fn next(stream: &mut BodyStream) -> Option<Result<Bytes, BodyError>> {
+            poll_fn(|cx| Pin::new(&mut *stream).poll_next(cx)).await
+        }
+        let base = server();
+        let fetch = Fetch::new(&format!("{base}/chunks")).body_limit(10);
+        let mut stream = fetch.stream().await.unwrap();
+        assert_eq!(next(&mut stream).await.unwrap().unwrap(), "abcd");
+        assert_eq!(next(&mut stream).await.unwrap().unwrap(), "abcd");
+        let e = next(&mut stream).await.unwrap().unwrap_err();
+        assert!(matches!(e, BodyError::TooLarge(10)));
+        assert!(next(&mut stream).await.is_none());
+
+        let fetch = Fetch::new(&format!("{base}/chunks")).body_limit(16);
+        assert_eq!(fetch.text().await.unwrap(), "abcd".repeat(4));
+    }
+
+    #[tokio::test]
+    async fn session() {
+        let base = server();
+        let session = Session::new();
+        let fetch = session.fetch(&format!("{base}/login")).max_redirects(1);
+        assert_eq!(fetch.text().await.unwrap(), "sid=1");
+        let fetch = session.fetch(&format!("{base}/cookie"));
+        assert_eq!(fetch.text().await.unwrap(), "sid=1");
+        assert_eq!(fetch_text(&format!("{base}/cookie")).await.unwrap(), "");
+    }
+
+    #[tokio::test]
+    async fn cache() {
+        let base = server();
+        let text = |path: &str| Fetch::new(&format!("{base}{path}")).cache(true).text();
+
+        // fresh hit
+        assert_eq!(text("/fresh").await.unwrap(), "0");
+        assert_eq!(text("/fresh").await.unwrap(), "0");
+        assert_eq!(FRESH.load(Ordering::Relaxed), 1);
+
+        // revalidated by `If-None-Match`
+        assert_eq!(text("/etag").await.unwrap(), "etag");
+        assert_eq!(text("/etag").await.unwrap(), "etag");
+        assert_eq!(NOT_MODIFIED.load(Ordering::Relaxed), 1);
+
+        // stale if failed
+        assert_eq!(text("/flaky").await.unwrap(), "flaky");
+        let fetch = Fetch::new(&format!("{base}/flaky")).cache(true);
+        let r = fetch.send().await.unwrap();
+        assert!(r.headers().contains_key(WARNING));
+        assert_eq!(read_text(r.into_body(), 16).await.unwrap(), "flaky");
+        let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let uri = format!("http://{}/closed", closed.local_addr().unwrap());
+        drop(closed);
+        let mut headers = HeaderMap::new();
+        headers.insert(CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+        cache::store(&uri, &headers, b"closed");
+        let r = Fetch::new(&uri).cache(true).send().await.unwrap();
+        assert!(r.headers().contains_key(WARNING));
+        assert_eq!(read_text(r.into_body(), 16).await.unwrap(), "closed");
+
+        // not stored
+        assert_eq!(text("/no-store").await.unwrap(), "0");
+        assert_eq!(text("/no-store").await.unwrap(), "1");
+        assert_eq!(text("/private").await.unwrap(), "private");
+        assert!(cache::get(&format!("{base}/private")).is_none());
+        let fetch = Fetch::new(&format!("{base}/cookie")).cache(true);
+        let fetch = fetch.header(COOKIE, HeaderValue::from_static("sid=1"));
+        assert_eq!(fetch.text().await.unwrap(), "sid=1");
+        assert!(cache::get(&format!("{base}/cookie")).is_none());
+        let fetch = Session::new().fetch(&format!("{base}/fresh")).cache(true);
+        assert_eq!(fetch.text().await.unwrap(), "1");
+    }
+}
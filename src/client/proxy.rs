@@ -0,0 +1,403 @@
+//! Outbound proxy, HTTP CONNECT or SOCKS5.
+//!
+//! Configs are stored in `proxy_cfg` table, one `k v` per row:
+//!
+//! * `global`: the default proxy, like `socks5://127.0.0.1:1080` or `http://u:p@10.0.0.1:3128`.
+//! * `no_proxy`: hosts to connect directly, separated by comma or whitespace. Supports domain like
+//!   `example.com` (includes subdomains), IP or CIDR like `10.0.0.0/8`, and `*` for all.
+//! * others: override the proxy of a unit, by unit name. The value `direct` disables proxy.
+use crate::db;
+use crate::remote;
+use crate::utils::base64_encode;
+use anyhow::{anyhow, Result};
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::io;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+fn db_init() {
+    db! {"
+        CREATE TABLE IF NOT EXISTS proxy_cfg
+        (k TEXT PRIMARY KEY, v TEXT)
+    "}
+    .unwrap();
+}
+fn db_cfg_set(k: &str, v: &str) {
+    db! {"
+        REPLACE INTO proxy_cfg
+        VALUES (?1, ?2)
+    ", [k, v]}
+    .unwrap();
+}
+fn db_cfg_delete(k: &str) {
+    db! {"
+        DELETE FROM proxy_cfg
+        WHERE k = ?
+    ", [k]}
+    .unwrap();
+}
+fn db_cfg_get() -> Vec<(String, String)> {
+    db! {"
+        SELECT * FROM proxy_cfg
+    ", [], (0, 1)}
+    .unwrap()
+}
+
+static CFG: Lazy<Mutex<HashMap<String, String>>> = Lazy::new(|| {
+    db_init();
+    Mutex::new(db_cfg_get().into_iter().collect())
+});
+
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+enum Kind {
+    Http,
+    Socks5,
+}
+
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct Proxy {
+    kind: Kind,
+    /// The proxy server, `host:port`.
+    addr: String,
+    auth: Option<(String, String)>,
+}
+
+impl Proxy {
+    /// Parse `http://[user:pass@]host[:port]` or `socks5://...`, the `socks5h` is also accepted.
+    ///
+    /// Domain names are always resolved by the proxy server.
+    pub fn parse(s: &str) -> Result<Self> {
+        let (scheme, rest) = s
+            .trim()
+            .split_once("://")
+            .ok_or_else(|| anyhow!("no scheme"))?;
+        let (kind, default_port) = match scheme {
+            "http" => (Kind::Http, 80),
+            "socks5" | "socks5h" => (Kind::Socks5, 1080),
+            _ => return Err(anyhow!("unsupported proxy scheme `{scheme}`")),
+        };
+        let rest = rest.trim_end_matches('/');
+        let (auth, host) = match rest.rsplit_once('@') {
+            Some((auth, host)) => {
+                let (user, pass) = auth.split_once(':').unwrap_or((auth, ""));
+                (Some((user.to_string(), pass.to_string())), host)
+            }
+            None => (None, rest),
+        };
+        if host.is_empty() || host.contains('/') {
+            return Err(anyhow!("invalid proxy host `{host}`"));
+        }
+        let addr = match host.rsplit_once(':') {
+            // "[::1]" has colons but no port
+            Some((_, port)) if !host.ends_with(']') => {
+                port.parse::<u16>()?;
+                host.to_string()
+            }
+            _ => format!("{host}:{default_port}"),
+        };
+        Ok(Proxy { kind, addr, auth })
+    }
+
+    /// The proxy server address, `host:port`.
+    pub fn addr(&self) -> &str {
+        &self.addr
+    }
+
+    /// Establish a tunnel to `host:port`, over the stream which is connected to the proxy.
+    ///
+    /// The `host` may be an IPv6 in brackets, same as `Uri::host`.
+    pub async fn tunnel<S>(&self, stream: &mut S, host: &str, port: u16) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        match self.kind {
+            Kind::Http => self.tunnel_http(stream, host, port).await,
+            Kind::Socks5 => self.tunnel_socks5(stream, host, port).await,
+        }
+    }
+
+    async fn tunnel_http<S>(&self, stream: &mut S, host: &str, port: u16) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut req = format!("CONNECT {host}:{port} HTTP/1.1\r\nHost: {host}:{port}\r\n");
+        if let Some((user, pass)) = &self.auth {
+            let credential = base64_encode(format!("{user}:{pass}").as_bytes());
+            write!(req, "Proxy-Authorization: Basic {credential}\r\n").unwrap();
+        }
+        req += "\r\n";
+        stream.write_all(req.as_bytes()).await?;
+        // read byte by byte, do not consume the data after response head
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            if head.len() >= 4096 {
+                return Err(error("proxy response head too large"));
+            }
+            head.push(stream.read_u8().await?);
+        }
+        // "HTTP/1.1 200 Connection established"
+        let line = head.split(|&b| b == b'\r').next().unwrap();
+        match line.split(|&b| b == b' ').nth(1) {
+            Some(b"200") => Ok(()),
+            _ => Err(error(&format!(
+                "proxy refused: {}",
+                String::from_utf8_lossy(line)
+            ))),
+        }
+    }
+
+    /// https://www.rfc-editor.org/rfc/rfc1928 and https://www.rfc-editor.org/rfc/rfc1929
+    async fn tunnel_socks5<S>(&self, stream: &mut S, host: &str, port: u16) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut reply = [0; 2];
+        match &self.auth {
+            Some(_) => stream.write_all(&[5, 2, 0, 2]).await?, // no auth or user pass
+            None => stream.write_all(&[5, 1, 0]).await?,
+        };
+        stream.read_exact(&mut reply).await?;
+        match (reply, &self.auth) {
+            ([5, 0], _) => {}
+            ([5, 2], Some((user, pass))) => {
+                if user.len() > 255 || pass.len() > 255 {
+                    return Err(error("socks5 credential too long"));
+                }
+                let mut buf = vec![1, user.len() as u8];
+                buf.extend(user.as_bytes());
+                buf.push(pass.len() as u8);
+                buf.extend(pass.as_bytes());
+                stream.write_all(&buf).await?;
+                stream.read_exact(&mut reply).await?;
+                if reply[1] != 0 {
+                    return Err(error("socks5 authentication failed"));
+                }
+            }
+            _ => return Err(error("socks5 no acceptable methods")),
+        }
+        let mut buf = vec![5, 1, 0]; // CONNECT
+        match host.trim_matches(['[', ']']).parse() {
+            Ok(IpAddr::V4(v)) => {
+                buf.push(1);
+                buf.extend(v.octets());
+            }
+            Ok(IpAddr::V6(v)) => {
+                buf.push(4);
+                buf.extend(v.octets());
+            }
+            Err(_) if host.len() <= 255 => {
+                buf.extend([3, host.len() as u8]);
+                buf.extend(host.as_bytes());
+            }
+            Err(_) => return Err(error("socks5 domain name too long")),
+        }
+        buf.extend(port.to_be_bytes());
+        stream.write_all(&buf).await?;
+        let mut head = [0; 4];
+        stream.read_exact(&mut head).await?;
+        if head[1] != 0 {
+            return Err(error(&format!("socks5 connect failed, reply {}", head[1])));
+        }
+        let len = match head[3] {
+            1 => 4,
+            4 => 16,
+            3 => stream.read_u8().await? as usize,
+            _ => return Err(error("socks5 invalid bound address")),
+        };
+        let mut bound = vec![0; len + 2]; // the bound address and port are not cared
+        stream.read_exact(&mut bound).await?;
+        Ok(())
+    }
+}
+
+fn error(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Select the proxy for a unit, falls back to `global`.
+pub fn select(unit: Option<&str>) -> Option<Proxy> {
+    let cfg = CFG.lock().unwrap();
+    let v = unit
+        .and_then(|k| cfg.get(k))
+        .or_else(|| cfg.get("global"))?;
+    match v.as_str() {
+        "direct" => None,
+        v => Proxy::parse(v).ok(), // validated while setting
+    }
+}
+
+/// Returns `true` if the host matches `no_proxy`.
+pub fn bypass(host: &str) -> bool {
+    let cfg = CFG.lock().unwrap();
+    let list = match cfg.get("no_proxy") {
+        Some(v) => v,
+        None => return false,
+    };
+    let host = host.trim_matches(['[', ']']).to_ascii_lowercase();
+    let ip = host.parse::<IpAddr>().ok();
+    let items = list.split(|c: char| c == ',' || c.is_whitespace());
+    items.filter(|v| !v.is_empty()).any(|item| {
+        if item == "*" {
+            return true;
+        }
+        if let (Some(ip), Some(net)) = (ip, remote::parse_net(item)) {
+            return remote::net_contains(net, ip);
+        }
+        let item = item.trim_start_matches("*.").trim_start_matches('.');
+        let item = item.to_ascii_lowercase();
+        match host.strip_suffix(&item) {
+            Some(v) => v.is_empty() || v.ends_with('.'),
+            None => false,
+        }
+    })
+}
+
+/// Set or delete (if `v` is empty) a config and store it.
+pub fn set_cfg(k: &str, v: &str) -> Result<()> {
+    if v.is_empty() {
+        let mut cfg = CFG.lock().unwrap();
+        db_cfg_delete(k);
+        cfg.remove(k);
+        return Ok(());
+    }
+    if k != "no_proxy" && v != "direct" {
+        Proxy::parse(v)?;
+    }
+    let mut cfg = CFG.lock().unwrap(); // the table is created by the first access
+    db_cfg_set(k, v);
+    cfg.insert(k.to_string(), v.to_string());
+    Ok(())
+}
+
+/// Display the configs, one `k v` per line.
+pub fn status() -> String {
+    let mut cfg = CFG.lock().unwrap().clone().into_iter().collect::<Vec<_>>();
+    cfg.sort();
+    let mut ret = String::new();
+    for (k, v) in cfg {
+        writeln!(ret, "{k} {v}").unwrap();
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::Fetch;
+    use axum::routing::{get, Router};
+    use std::future::Future;
+    use std::net::SocketAddr;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+    /// A local server responds `ok`.
+    fn target() -> SocketAddr {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = Router::new().route("/", get(|| async { "ok" }));
+        let server = axum::Server::from_tcp(listener).unwrap();
+        tokio::spawn(server.serve(app.into_make_service()));
+        addr
+    }
+
+    /// Start a local proxy, the handshakes are sent to the channel as the requested target.
+    async fn start<F, Fut>(serve: F) -> (SocketAddr, UnboundedReceiver<String>)
+    where
+        F: Fn(TcpStream, UnboundedSender<String>) -> Fut + Send + 'static,
+        Fut: Future<Output = io::Result<()>> + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(serve(stream, tx.clone()));
+            }
+        });
+        (addr, rx)
+    }
+
+    async fn relay(mut stream: TcpStream, target: &str) -> io::Result<()> {
+        let mut upstream = TcpStream::connect(target).await?;
+        tokio::io::copy_bidirectional(&mut stream, &mut upstream).await?;
+        Ok(())
+    }
+
+    async fn http_proxy(mut stream: TcpStream, tx: UnboundedSender<String>) -> io::Result<()> {
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            head.push(stream.read_u8().await?);
+        }
+        let head = String::from_utf8(head).unwrap();
+        let target = head.split(' ').nth(1).unwrap().to_string();
+        let auth = head
+            .lines()
+            .find_map(|v| v.strip_prefix("Proxy-Authorization: "));
+        tx.send(format!("{target} {}", auth.unwrap_or_default()))
+            .unwrap();
+        stream
+            .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+            .await?;
+        relay(stream, &target).await
+    }
+
+    async fn socks5_proxy(mut stream: TcpStream, tx: UnboundedSender<String>) -> io::Result<()> {
+        let mut head = [0; 2];
+        stream.read_exact(&mut head).await?;
+        let mut methods = vec![0; head[1] as usize];
+        stream.read_exact(&mut methods).await?;
+        stream.write_all(&[5, 0]).await?;
+        let mut head = [0; 4];
+        stream.read_exact(&mut head).await?;
+        let host = match head[3] {
+            1 => IpAddr::from(stream.read_u32().await?.to_be_bytes()).to_string(),
+            3 => {
+                let mut host = vec![0; stream.read_u8().await? as usize];
+                stream.read_exact(&mut host).await?;
+                String::from_utf8(host).unwrap()
+            }
+            _ => unimplemented!(),
+        };
+        let target = format!("{host}:{}", stream.read_u16().await?);
+        tx.send(target.clone()).unwrap();
+        stream.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]).await?;
+        relay(stream, &target).await
+    }
+
+    #[tokio::test]
+    async fn http_connect() {
+        let target = target();
+        let (proxy, mut rx) = start(http_proxy).await;
+        set_cfg("test_http", &format!("http://u:p@{proxy}")).unwrap();
+        let uri = format!("http://{target}/");
+        let text = Fetch::new(&uri).unit("test_http").text().await.unwrap();
+        assert_eq!(text, "ok");
+        let auth = base64_encode(b"u:p");
+        assert_eq!(rx.recv().await.unwrap(), format!("{target} Basic {auth}"));
+        set_cfg("test_http", "").unwrap();
+    }
+
+    #[tokio::test]
+    async fn socks5() {
+        let target = target();
+        let (proxy, mut rx) = start(socks5_proxy).await;
+        set_cfg("test_socks5", &format!("socks5://{proxy}")).unwrap();
+        let uri = format!("http://{target}/");
+        let text = Fetch::new(&uri).unit("test_socks5").text().await.unwrap();
+        assert_eq!(text, "ok");
+        assert_eq!(rx.recv().await.unwrap(), target.to_string());
+
+        // domain names are resolved by the proxy
+        let uri = format!("http://localhost:{}/", target.port());
+        let text = Fetch::new(&uri).unit("test_socks5").text().await.unwrap();
+        assert_eq!(text, "ok");
+        assert_eq!(
+            rx.recv().await.unwrap(),
+            format!("localhost:{}", target.port())
+        );
+        set_cfg("test_socks5", "").unwrap();
+    }
+}
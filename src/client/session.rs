@@ -0,0 +1,51 @@
+//! Session with cookie jar and default headers, for multi-step scripted web flows.
+use super::cookie::CookieJar;
+use super::{Fetch, ToRequest};
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+use std::sync::{Arc, Mutex};
+
+/// Share cookies and default headers between requests.
+///
+/// # Example
+///
+/// ```
+/// let session = Session::new().header(USER_AGENT, HeaderValue::from_static("Chrome"));
+/// let r = session.fetch(login_request).max_redirects(3).send().await?;
+/// let ticket = r.extensions().get::<Redirects>().and_then(|v| v.query("ticket"));
+/// let text = session.fetch("https://example.com/home").text().await?; // with cookies
+/// ```
+#[derive(Clone, Default)]
+pub struct Session {
+    jar: Arc<Mutex<CookieJar>>,
+    headers: HeaderMap,
+    unit: Option<&'static str>,
+}
+
+impl Session {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Default header of all requests in this session.
+    pub fn header(mut self, k: HeaderName, v: HeaderValue) -> Self {
+        self.headers.insert(k, v);
+        self
+    }
+
+    /// Use the proxy config of the unit, see `client::proxy`.
+    pub fn unit(mut self, v: &'static str) -> Self {
+        self.unit = Some(v);
+        self
+    }
+
+    /// Create a `Fetch` within this session, the options can still be changed.
+    pub fn fetch(&self, request: impl ToRequest) -> Fetch {
+        let mut fetch = Fetch::new(request);
+        for (k, v) in &self.headers {
+            fetch = fetch.header(k.clone(), v.clone());
+        }
+        fetch.opts.unit = self.unit;
+        fetch.opts.jar = Some(self.jar.clone());
+        fetch
+    }
+}
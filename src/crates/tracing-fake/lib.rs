@@ -1,7 +1,37 @@
+use std::fmt::Arguments;
 use std::future::Future;
 use std::pin::Pin;
+use std::sync::OnceLock;
 use std::task::{Context, Poll};
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Level(u8);
+
+impl Level {
+    pub const ERROR: Level = Level(1);
+    pub const WARN: Level = Level(2);
+    pub const INFO: Level = Level(3);
+    pub const DEBUG: Level = Level(4);
+    pub const TRACE: Level = Level(5);
+}
+
+/// The receiver of events, the arguments are `(level, module_path, message)`.
+pub type Sink = fn(Level, &'static str, Arguments);
+
+static SINK: OnceLock<Sink> = OnceLock::new();
+
+/// Set the receiver of events, only the first call takes effect. Events are dropped before set.
+pub fn set_sink(sink: Sink) {
+    SINK.set(sink).ok();
+}
+
+#[doc(hidden)]
+pub fn __emit(level: Level, module_path: &'static str, args: Arguments) {
+    if let Some(sink) = SINK.get() {
+        sink(level, module_path, args);
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct Span;
 
@@ -46,13 +76,28 @@ pub mod instrument {
     pub use super::Instrumented;
 }
 
+/// Events with structured fields are not formatted, the source tokens are emitted instead.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __event {
+    ($level:expr, target: $target:expr, $($arg:tt)+) => {
+        $crate::__event!($level, $($arg)+)
+    };
+    ($level:expr, $fmt:literal $($arg:tt)*) => {
+        $crate::__emit($level, module_path!(), format_args!($fmt $($arg)*))
+    };
+    ($level:expr, $($arg:tt)+) => {
+        $crate::__emit($level, module_path!(), format_args!("{}", stringify!($($arg)+)))
+    };
+}
+
 #[rustfmt::skip]
 mod macros {
-    #[macro_export] macro_rules! info { ($($arg:tt)*) => {{}}; }
-    #[macro_export] macro_rules! warn { ($($arg:tt)*) => {{}}; }
-    #[macro_export] macro_rules! trace { ($($arg:tt)*) => {{}}; }
-    #[macro_export] macro_rules! debug { ($($arg:tt)*) => {{}}; }
-    #[macro_export] macro_rules! error { ($($arg:tt)*) => {{}}; }
+    #[macro_export] macro_rules! info { ($($arg:tt)+) => { $crate::__event!($crate::Level::INFO, $($arg)+) }; }
+    #[macro_export] macro_rules! warn { ($($arg:tt)+) => { $crate::__event!($crate::Level::WARN, $($arg)+) }; }
+    #[macro_export] macro_rules! trace { ($($arg:tt)+) => { $crate::__event!($crate::Level::TRACE, $($arg)+) }; }
+    #[macro_export] macro_rules! debug { ($($arg:tt)+) => { $crate::__event!($crate::Level::DEBUG, $($arg)+) }; }
+    #[macro_export] macro_rules! error { ($($arg:tt)+) => { $crate::__event!($crate::Level::ERROR, $($arg)+) }; }
     #[macro_export] macro_rules! trace_span { ($($arg:tt)*) => {{ ::tracing::Span }}; }
     #[macro_export] macro_rules! debug_span { ($($arg:tt)*) => {{ ::tracing::Span }}; }
 }
@@ -1,29 +1,148 @@
+use crate::log;
 use once_cell::sync::Lazy;
+use rusqlite::types::Value;
 use rusqlite::Connection;
-use std::sync::Mutex;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Mutex};
+use std::thread;
+use std::time::Duration;
 
-/// # Use `db!()` macro instead of access directly!
-pub static DB_: Lazy<Mutex<Connection>> = Lazy::new(|| {
-    let path = std::env::current_exe().unwrap().with_extension("db");
-    let db = Connection::open(path).unwrap();
+/// The database file, next to the executable.
+pub fn path() -> PathBuf {
+    std::env::current_exe().unwrap().with_extension("db")
+}
+
+fn open() -> rusqlite::Result<Connection> {
+    let db = Connection::open(path())?;
 
     // Optimize for Performance
     // https://www.sqlite.org/speed.html
     // https://www.sqlite.org/pragma.html
 
     // The `WAL` mode will improve writing but slow down reading a little.
-    db.pragma_update(None, "journal_mode", "TRUNCATE").unwrap();
+    db.pragma_update(None, "journal_mode", "TRUNCATE")?;
     // db.pragma_update(None, "journal_mode", "WAL").unwrap();
     // TODO: use WAL mode, switch to TRUNCATE before backup
 
     // Sync less often than `FULL` and still safe enough.
-    db.pragma_update(None, "synchronous", "NORMAL").unwrap();
+    db.pragma_update(None, "synchronous", "NORMAL")?;
 
     // We don't need to touch db file during program execution.
-    db.pragma_update(None, "locking_mode", "EXCLUSIVE").unwrap();
+    db.pragma_update(None, "locking_mode", "EXCLUSIVE")?;
+
+    Ok(db)
+}
+
+/// # Use `db!()` macro instead of access directly!
+pub static DB_: Lazy<Mutex<Connection>> = Lazy::new(|| Mutex::new(open().unwrap()));
+
+/// Close and open again, `f` is called between.
+fn reopen<T>(db: &mut Connection, f: impl FnOnce() -> T) -> rusqlite::Result<T> {
+    // close the current one to release the exclusive lock
+    *db = Connection::open_in_memory()?;
+    let ret = f();
+    *db = open()?;
+    Ok(ret)
+}
+
+/// Replace the database file by another one and reopen, used by restoring.
+pub fn replace(src: &Path) -> io::Result<()> {
+    let mut db = DB_.lock().unwrap();
+    reopen(&mut db, || std::fs::rename(src, path())).map_err(io::Error::other)?
+}
 
-    Mutex::new(db)
-});
+/// Run a migration once in a transaction, the applied names are recorded in `migrations` table.
+///
+/// For the changes can not be done by `CREATE TABLE IF NOT EXISTS` or `ALTER TABLE` with `.ok()`,
+/// like converting or moving rows. It's also applied to new databases, where it has nothing to do.
+pub fn migrate(
+    name: &str,
+    f: impl FnOnce(&Connection) -> rusqlite::Result<()>,
+) -> rusqlite::Result<()> {
+    let mut db = DB_.lock().unwrap();
+    db.execute_batch(
+        "CREATE TABLE IF NOT EXISTS migrations (name TEXT PRIMARY KEY, time INTEGER)",
+    )?;
+    let tx = db.transaction()?;
+    let sql = "SELECT count(*) FROM migrations WHERE name = ?";
+    if tx.query_row(sql, [name], |r| r.get::<_, i64>(0))? > 0 {
+        return Ok(());
+    }
+    f(&tx)?;
+    let sql = "INSERT INTO migrations VALUES (?, strftime('%s','now'))";
+    tx.execute(sql, [name])?;
+    tx.commit()
+}
+
+/// Used by migrations, the old tables may not exist.
+pub fn table_exists(db: &Connection, name: &str) -> rusqlite::Result<bool> {
+    let sql = "SELECT count(*) FROM sqlite_master WHERE type = 'table' AND name = ?";
+    Ok(db.query_row(sql, [name], |r| r.get::<_, i64>(0))? > 0)
+}
+
+pub struct Rows {
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<Value>>,
+    /// There are more rows after these.
+    pub more: bool,
+}
+
+/// Run a statement with `PRAGMA query_only`, for the SQL console of admin.
+///
+/// Interrupted after the timeout, to not hold the lock too long.
+pub fn query_readonly(
+    sql: &str,
+    offset: usize,
+    limit: usize,
+    timeout: Duration,
+) -> rusqlite::Result<Rows> {
+    let mut db = DB_.lock().unwrap();
+    let (tx, rx) = mpsc::channel::<()>();
+    let handle = db.get_interrupt_handle();
+    thread::spawn(move || {
+        // disconnected if finished in time
+        if let Err(mpsc::RecvTimeoutError::Timeout) = rx.recv_timeout(timeout) {
+            handle.interrupt();
+        }
+    });
+    db.pragma_update(None, "query_only", true)?;
+    let ret = (|| {
+        let mut stmt = db.prepare(sql)?;
+        let columns = stmt.column_names().into_iter().map(String::from).collect();
+        let n = stmt.column_count();
+        let mut rows = stmt.query([])?;
+        for _ in 0..offset {
+            if rows.next()?.is_none() {
+                break;
+            }
+        }
+        let mut ret = Rows {
+            columns,
+            rows: Vec::new(),
+            more: false,
+        };
+        while let Some(row) = rows.next()? {
+            if ret.rows.len() == limit {
+                ret.more = true;
+                break;
+            }
+            ret.rows
+                .push((0..n).map(|i| row.get(i)).collect::<Result<_, _>>()?);
+        }
+        Ok(ret)
+    })();
+    drop(tx);
+    // may be interrupted if timeout just now, the retry will not be
+    let reset = { db.pragma_update(None, "query_only", false) }
+        .or_else(|_| db.pragma_update(None, "query_only", false))
+        .or_else(|_| reopen(&mut db, || ())); // a new connection is writable
+    drop(db); // the log may be flushed into database
+    if let Err(e) = &reset {
+        log!(Error, "failed to reset query_only: {e}");
+    }
+    reset.and(ret)
+}
 
 #[macro_export]
 macro_rules! db {
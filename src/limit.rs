@@ -0,0 +1,243 @@
+//! Proactive traffic restriction, by client IP.
+//!
+//! Every limit is a token bucket with `(rate, burst)`, the `rate` is tokens per second. There are
+//! some special keys in `limit_cfg` table:
+//!
+//! * `conn`: concurrent connections per IP, only the `burst` is used.
+//! * `handshake`: TLS handshakes per IP.
+//! * `chat_post`: posts per connection of chat rooms, see `Throttle`.
+//! * others: requests per IP of the route group, usually the unit name.
+use crate::db;
+use crate::remote;
+use axum::http::header::RETRY_AFTER;
+use axum::http::{Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Router;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const DEFAULT_CFG: &[(&str, f64, f64)] = &[
+    ("conn", 0.0, 64.0),
+    ("handshake", 2.0, 32.0),
+    ("admin", 1.0, 16.0),
+    ("chat", 8.0, 64.0),
+    ("chat_post", 2.0, 16.0),
+    ("health", 1.0, 16.0),
+    ("info", 2.0, 16.0),
+    ("magazine", 2.0, 16.0),
+    ("paste", 2.0, 32.0),
+    ("qqbot", 1.0, 16.0),
+    ("record", 1.0, 16.0),
+];
+const FALLBACK_CFG: (f64, f64) = (4.0, 32.0);
+/// The longest `Retry-After`, also for the zero rate.
+const MAX_WAIT: Duration = Duration::from_secs(60);
+
+fn db_init() {
+    db! {"
+        CREATE TABLE IF NOT EXISTS limit_cfg
+        (k TEXT PRIMARY KEY, rate REAL, burst REAL)
+    "}
+    .unwrap();
+}
+fn db_cfg_set(k: &str, rate: f64, burst: f64) {
+    db! {"
+        REPLACE INTO limit_cfg
+        VALUES (?1, ?2, ?3)
+    ", [k, rate, burst]}
+    .unwrap();
+}
+fn db_cfg_get() -> Vec<(String, f64, f64)> {
+    db! {"
+        SELECT * FROM limit_cfg
+    ", [], (0, 1, 2)}
+    .unwrap()
+}
+
+static CFG: Lazy<Mutex<HashMap<String, (f64, f64)>>> = Lazy::new(|| {
+    db_init();
+    let mut cfg = HashMap::new();
+    for &(k, rate, burst) in DEFAULT_CFG {
+        cfg.insert(k.to_string(), (rate, burst));
+    }
+    cfg.extend(
+        db_cfg_get()
+            .into_iter()
+            .map(|(k, rate, burst)| (k, (rate, burst))),
+    );
+    Mutex::new(cfg)
+});
+
+fn cfg(k: &str) -> (f64, f64) {
+    CFG.lock().unwrap().get(k).copied().unwrap_or(FALLBACK_CFG)
+}
+
+/// Both should be finite and non-negative.
+pub fn check_cfg(rate: f64, burst: f64) -> bool {
+    [rate, burst].iter().all(|v| v.is_finite() && *v >= 0.0)
+}
+
+/// Set the limit of a key and store it.
+pub fn set_cfg(k: &str, rate: f64, burst: f64) {
+    let mut cfg = CFG.lock().unwrap(); // the table is created by the first access
+    db_cfg_set(k, rate, burst);
+    cfg.insert(k.to_string(), (rate, burst));
+}
+
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn new(burst: f64) -> Self {
+        Bucket {
+            tokens: burst,
+            last: Instant::now(),
+        }
+    }
+
+    /// Take a token, or returns how long to wait for the next one.
+    fn take(&mut self, (rate, burst): (f64, f64)) -> Result<(), Duration> {
+        let now = Instant::now();
+        self.tokens = burst.min(self.tokens + rate * (now - self.last).as_secs_f64());
+        self.last = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            let wait = (1.0 - self.tokens) / rate; // infinity for the zero rate
+            Err(Duration::try_from_secs_f64(wait).map_or(MAX_WAIT, |v| v.min(MAX_WAIT)))
+        }
+    }
+}
+
+/// Buckets are forgotten in batch when reached this, such as a spread of IPv6 addresses.
+const MAX_BUCKETS: usize = 4096;
+static BUCKETS: Lazy<Mutex<HashMap<(&str, IpAddr), Bucket>>> = Lazy::new(Default::default);
+
+fn take(k: &'static str, ip: IpAddr) -> Result<(), Duration> {
+    let (rate, burst) = cfg(k);
+    let now = Instant::now();
+    let mut buckets = BUCKETS.lock().unwrap();
+    if buckets.len() >= MAX_BUCKETS {
+        // forget the buckets which are full again, they are the same as new buckets
+        let cfg = CFG.lock().unwrap().clone();
+        buckets.retain(|(k, _), v| {
+            let (rate, burst) = cfg.get(*k).copied().unwrap_or(FALLBACK_CFG);
+            v.tokens + rate * (now - v.last).as_secs_f64() < burst
+        });
+        // then the least recently used ones, to not sweep again on every request
+        if buckets.len() > MAX_BUCKETS / 2 {
+            let mut lasts = buckets.values().map(|v| v.last).collect::<Vec<_>>();
+            let n = lasts.len() - MAX_BUCKETS / 2;
+            let oldest = *lasts.select_nth_unstable(n).1;
+            buckets.retain(|_, v| v.last >= oldest);
+        }
+    }
+    let bucket = buckets.entry((k, ip)).or_insert_with(|| Bucket::new(burst));
+    bucket.take((rate, burst))
+}
+
+/// A token bucket owned by something else than IP, such as a connection.
+pub struct Throttle {
+    k: &'static str,
+    bucket: Bucket,
+}
+
+impl Throttle {
+    pub fn new(k: &'static str) -> Self {
+        Throttle {
+            k,
+            bucket: Bucket::new(cfg(k).1),
+        }
+    }
+
+    /// Take a token, or returns how long to wait for the next one.
+    pub fn take(&mut self) -> Result<(), Duration> {
+        self.bucket.take(cfg(self.k))
+    }
+}
+
+static CONNS: Lazy<Mutex<HashMap<IpAddr, u32>>> = Lazy::new(Default::default);
+
+/// Hold this while the connection is alive.
+pub struct ConnGuard(IpAddr);
+
+impl Drop for ConnGuard {
+    fn drop(&mut self) {
+        let mut conns = CONNS.lock().unwrap();
+        let count = conns.get_mut(&self.0).unwrap();
+        *count -= 1;
+        if *count == 0 {
+            conns.remove(&self.0);
+        }
+    }
+}
+
+/// Count a new connection, returns `None` if reached the concurrent limit.
+pub fn connect(ip: IpAddr) -> Option<ConnGuard> {
+    let (_, max) = cfg("conn");
+    let mut conns = CONNS.lock().unwrap();
+    let count = conns.entry(ip).or_insert(0);
+    if *count as f64 >= max {
+        return None;
+    }
+    *count += 1;
+    Some(ConnGuard(ip))
+}
+
+/// Returns `false` if the TLS handshake rate limit was reached.
+pub fn handshake(ip: IpAddr) -> bool {
+    take("handshake", ip).is_ok()
+}
+
+/// Apply the requests limit to all routes of a router.
+///
+/// # Example
+///
+/// ```
+/// let app = Router::new().merge(limit::group("admin", units::admin::service()));
+/// ```
+pub fn group(k: &'static str, router: Router) -> Router {
+    router.layer(axum::middleware::from_fn(move |req, next| {
+        check(k, req, next)
+    }))
+}
+
+async fn check<B>(k: &'static str, req: Request<B>, next: Next<B>) -> Response {
+    let ip = remote::resolve(req.headers(), req.extensions());
+    match take(k, ip) {
+        Ok(()) => next.run(req).await,
+        Err(wait) => (
+            StatusCode::TOO_MANY_REQUESTS,
+            [(RETRY_AFTER, wait.as_secs() + 1)],
+            "too many requests",
+        )
+            .into_response(),
+    }
+}
+
+/// Display the config and status, one line per key.
+pub fn status() -> String {
+    let mut ret = String::new();
+    let mut cfg = CFG.lock().unwrap().clone().into_iter().collect::<Vec<_>>();
+    cfg.sort_by(|a, b| a.0.cmp(&b.0));
+    for (k, (rate, burst)) in cfg {
+        writeln!(ret, "{k} {rate} {burst}").unwrap();
+    }
+    let conns = CONNS.lock().unwrap();
+    let total = conns.values().sum::<u32>();
+    writeln!(ret, "# {total} connections from {} IPs", conns.len()).unwrap();
+    if let Some((ip, count)) = conns.iter().max_by_key(|v| v.1) {
+        writeln!(ret, "# top {ip} with {count} connections").unwrap();
+    }
+    let buckets = BUCKETS.lock().unwrap();
+    writeln!(ret, "# {} buckets", buckets.len()).unwrap();
+    ret
+}
@@ -0,0 +1,262 @@
+//! Leveled log of units, fed by `log!`, `care!` and the `tracing` macros of dependencies.
+//!
+//! Records are buffered in memory and flushed into `log` table in batch, like `access`. The
+//! `warn` and more severe records are also printed to stderr.
+//!
+//! Retention by level: `error` and `warn` for 30 days, `info` for 7 days, `debug` for 1 day. The
+//! `trace` records, and the `debug` records of dependencies are dropped.
+use crate::ticker::Ticker;
+use crate::utils::log_escape;
+use crate::{database, db};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+use tokio::sync::broadcast::{self, Sender};
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    Error = 1,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    const ALL: [Level; 5] = [
+        Level::Error,
+        Level::Warn,
+        Level::Info,
+        Level::Debug,
+        Level::Trace,
+    ];
+
+    fn from_u8(v: u8) -> Level {
+        Level::ALL[(v.clamp(1, 5) - 1) as usize]
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = ["error", "warn", "info", "debug", "trace"][*self as usize - 1];
+        f.pad(s)
+    }
+}
+
+#[derive(Clone, Serialize)]
+pub struct Record {
+    /// Unix timestamp in seconds.
+    pub time: u64,
+    pub level: Level,
+    /// The unit name, or the crate name for dependencies.
+    pub unit: String,
+    pub msg: String,
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} | {:5} | {} | {}",
+            self.time,
+            self.level,
+            self.unit,
+            log_escape(&self.msg)
+        )
+    }
+}
+
+fn db_init() {
+    db! {"
+        CREATE TABLE IF NOT EXISTS log
+        (time INTEGER, level INTEGER, unit TEXT, msg TEXT)
+    "}
+    .unwrap();
+    // the health log moved into here, and the html escaping moved to rendering
+    database::migrate("log_health_log", |db| {
+        if !database::table_exists(db, "health_log")? {
+            return Ok(());
+        }
+        db.execute_batch(
+            r#"
+            INSERT INTO log
+            SELECT time, 3, 'health', id || ' | ' || replace(replace(replace(replace(replace(
+                ret, '&lt;', '<'), '&gt;', '>'), '&quot;', '"'), '&#x27;', ''''), '&amp;', '&')
+            FROM health_log;
+            DROP TABLE health_log;
+            "#,
+        )
+    })
+    .unwrap();
+}
+fn db_insert(r: Record) {
+    db! {"
+        INSERT INTO log
+        VALUES (?1, ?2, ?3, ?4)
+    ", [r.time, r.level as u8, r.unit, r.msg]}
+    .unwrap();
+}
+fn db_search(level: u8, unit: &str, pattern: &str, limit: u32) -> Vec<Record> {
+    let rows = db! {"
+        SELECT * FROM log
+        WHERE level <= ?1 AND (?2 = '' OR unit = ?2) AND msg LIKE ?3 ESCAPE '\\'
+        ORDER BY time DESC, rowid DESC
+        LIMIT ?4
+    ", [level, unit, pattern, limit], (0, 1, 2, 3)}
+    .unwrap();
+    let to_record = |(time, level, unit, msg)| Record {
+        time,
+        level: Level::from_u8(level),
+        unit,
+        msg,
+    };
+    rows.into_iter().map(to_record).collect()
+}
+fn db_clean() {
+    db! {"
+        DELETE FROM log
+        WHERE strftime('%s','now') - time > 3600 * 24 *
+            CASE WHEN level <= 2 THEN 30 WHEN level = 3 THEN 7 ELSE 1 END
+    "}
+    .unwrap();
+}
+
+/// Records more verbose than this are dropped.
+const MAX_LEVEL: Level = Level::Debug;
+
+static BUF: Mutex<Vec<Record>> = Mutex::new(Vec::new());
+static TAIL: Lazy<Sender<Record>> = Lazy::new(|| broadcast::channel(64).0);
+static DB_INIT: Lazy<()> = Lazy::new(db_init);
+
+fn flush() {
+    Lazy::force(&DB_INIT);
+    let records = std::mem::take(&mut *BUF.lock().unwrap());
+    for r in records {
+        db_insert(r);
+    }
+}
+
+/// The unit name from `module_path!()`.
+///
+/// `ksite::units::qqbot::base` into `qqbot`, `ksite::access` into `access`, `ricq::client` into
+/// `ricq`.
+fn unit_of(module_path: &str) -> &str {
+    let mut parts = module_path.split("::");
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some("ksite"), Some("units"), Some(v)) => v,
+        (Some("ksite"), Some(v), _) => v,
+        (v, _, _) => v.unwrap_or_default(),
+    }
+}
+
+/// Push a record, use `log!` or `care!` instead of calling this directly.
+pub fn push(level: Level, module_path: &str, msg: String) {
+    if level > MAX_LEVEL {
+        return;
+    }
+    let record = Record {
+        time: UNIX_EPOCH.elapsed().unwrap().as_secs(),
+        level,
+        unit: unit_of(module_path).to_string(),
+        msg,
+    };
+    if level <= Level::Warn {
+        eprintln!("[{}] {} | {}", record.level, record.unit, record.msg);
+    }
+    TAIL.send(record.clone()).ok(); // no receivers is fine
+    let len = {
+        let mut buf = BUF.lock().unwrap();
+        buf.push(record);
+        buf.len()
+    };
+    if len >= 256 {
+        // may be called out of the runtime, such as the threads of dependencies
+        match tokio::runtime::Handle::try_current() {
+            Ok(rt) => drop(rt.spawn_blocking(flush)),
+            Err(_) => flush(),
+        }
+    }
+}
+
+/// Receive the events of `tracing` macros, which are used by dependencies.
+///
+/// The table is created here, to migrate before other units.
+pub fn init() {
+    Lazy::force(&DB_INIT);
+    tracing::set_sink(|level, module_path, args| {
+        let level = match level {
+            tracing::Level::ERROR => Level::Error,
+            tracing::Level::WARN => Level::Warn,
+            tracing::Level::INFO => Level::Info,
+            _ => return, // too verbose, such as the connection states of hyper
+        };
+        push(level, module_path, args.to_string());
+    });
+}
+
+#[macro_export]
+/// Push a log record of the current unit.
+///
+/// # Example
+///
+/// ```
+/// log!(Info, "login by {method}");
+/// ```
+macro_rules! log {
+    ($level:ident, $($arg:tt)+) => {
+        $crate::log::push($crate::log::Level::$level, module_path!(), format!($($arg)+))
+    };
+}
+
+#[derive(Deserialize)]
+pub struct Filter {
+    /// The most verbose level to include.
+    pub level: Option<Level>,
+    /// Empty for all units.
+    #[serde(default)]
+    pub unit: String,
+    /// Keyword in message, case-insensitive for ASCII like `LIKE`.
+    #[serde(default)]
+    pub q: String,
+}
+
+impl Filter {
+    pub fn matches(&self, r: &Record) -> bool {
+        r.level <= self.level.unwrap_or(MAX_LEVEL)
+            && (self.unit.is_empty() || r.unit == self.unit)
+            && r.msg
+                .to_ascii_lowercase()
+                .contains(&self.q.to_ascii_lowercase())
+    }
+}
+
+/// Search records by the filter, the newest first.
+pub fn search(filter: &Filter, limit: u32) -> Vec<Record> {
+    flush();
+    let level = filter.level.unwrap_or(MAX_LEVEL) as u8;
+    // the same as `contains` in `Filter::matches`
+    let q = filter.q.replace('\\', "\\\\");
+    let q = q.replace('%', "\\%").replace('_', "\\_");
+    db_search(level, &filter.unit, &format!("%{q}%"), limit)
+}
+
+/// Subscribe the new records.
+pub fn tail() -> broadcast::Receiver<Record> {
+    TAIL.subscribe()
+}
+
+static TICKER: Lazy<Ticker> = Lazy::new(|| Ticker::new_p8(&[(3, 20, 0)]));
+pub async fn tick() {
+    let clean = TICKER.tick();
+    tokio::task::spawn_blocking(move || {
+        flush();
+        if clean {
+            db_clean();
+        }
+    })
+    .await
+    .unwrap();
+}
@@ -1,5 +1,11 @@
+mod access;
 mod auth;
+mod client;
 mod database;
+mod limit;
+mod log;
+mod remote;
+mod template;
 mod ticker;
 mod tls;
 mod units;
@@ -10,6 +16,8 @@ use std::net::SocketAddr;
 use std::process;
 use std::thread;
 use std::time::Duration;
+use tower_http::compression::predicate::{NotForContentType, Predicate, SizeAbove};
+use tower_http::compression::CompressionLayer;
 
 #[tokio::main]
 async fn main() {
@@ -17,8 +25,7 @@ async fn main() {
     println!("{} v{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
     println!("enter :q to quit");
     println!("authorization token = {}", *auth::TOKEN);
-
-    // db_upgrade(); // uncomment this if we need to upgrade database
+    log::init();
 
     thread::spawn(|| loop {
         let buf = &mut String::new();
@@ -34,16 +41,27 @@ async fn main() {
         println!("server address = {addr}");
 
         let app = Router::new()
-            .merge(units::admin::service())
-            .merge(units::chat::service())
-            .merge(units::health::service())
-            .merge(units::info::service())
-            .merge(units::magazine::service())
-            .merge(units::paste::service())
-            // .merge(units::paste_next::service())
-            .merge(units::qqbot::service())
-            .into_make_service();
-        // .into_make_service_with_connect_info::<SocketAddr>();
+            .merge(units::mount("admin", units::admin::service()))
+            .merge(units::mount("chat", units::chat::service()))
+            .merge(units::mount("health", units::health::service()))
+            .merge(units::mount("info", units::info::service()))
+            .merge(units::mount("magazine", units::magazine::service()))
+            .merge(units::mount("paste", units::paste::service()))
+            // .merge(units::mount("paste", units::paste_next::service()))
+            .merge(units::mount("qqbot", units::qqbot::service()))
+            .merge(units::mount("record", units::record::service()))
+            .layer(axum::middleware::from_fn(access::layer))
+            .layer(
+                CompressionLayer::new().compress_when(
+                    SizeAbove::new(256)
+                        .and(NotForContentType::IMAGES)
+                        .and(NotForContentType::const_new("text/event-stream"))
+                        // backups and chat blobs, compressed or encrypted already
+                        .and(NotForContentType::const_new("application/octet-stream"))
+                        .and(NotForContentType::const_new("application/gzip")),
+                ),
+            )
+            .into_make_service(); // `remote::Peer` is inserted by `tls::serve`
 
         // axum::Server::bind(&addr).serve(app).await.unwrap();
         tls::serve(&addr, app).await;
@@ -57,10 +75,16 @@ async fn main() {
         loop {
             interval.tick().await;
             let _ = tokio::join!(
-                units::health::tick(),
-                units::magazine::tick(),
-                // units::paste_next::tick(),
-                units::qqbot::tick(),
+                access::tick(),
+                client::cache::tick(),
+                log::tick(),
+                units::tick("admin", units::admin::tick()),
+                units::tick("chat", units::chat::tick()),
+                units::chat::sync(),
+                units::tick("health", units::health::tick()),
+                units::tick("magazine", units::magazine::tick()),
+                // units::tick("paste", units::paste_next::tick()),
+                units::tick("qqbot", units::qqbot::tick()),
             );
         }
     };
@@ -71,30 +95,10 @@ async fn main() {
     //     tokio::time::sleep(Duration::from_millis(1000)).await;
     //     loop {
     //         tokio::time::sleep(Duration::from_millis(500)).await;
-    //         let a = utils::fetch_text("https://127.0.0.1:9304/info").await;
+    //         let a = client::Fetch::new("https://127.0.0.1:9304/info").text().await;
     //         dbg!(a).ok();
     //     }
     // });
 
     tokio::join!(server, oscillator);
 }
-
-/// Deal with database upgrade.
-#[cfg(feature = "db_upgrade")]
-fn db_upgrade() {
-    const CURRENT_VER: &str = env!("CARGO_PKG_VERSION");
-    fn db_set(k: &str, v: &[u8]) {
-        db!("REPLACE INTO admin VALUES (?1, ?2)", [k, v]).unwrap();
-    }
-    fn db_get(k: &str) -> Option<(Vec<u8>,)> {
-        db!("SELECT v FROM admin WHERE k = ?", [k], ^(0)).ok()
-    }
-    if !matches!(
-        db_get("version"),
-        Some((v,)) if v == CURRENT_VER.as_bytes()
-    ) {
-        println!("upgrade database structure to v{CURRENT_VER}");
-        db_set("version", CURRENT_VER.as_bytes());
-        db!("DROP TABLE health_list").unwrap();
-    }
-}
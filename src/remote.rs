@@ -0,0 +1,199 @@
+//! Real client address, PROXY protocol & forwarded headers.
+//!
+//! The trusted upstreams are read from the `trusted_proxies` key in `admin` table, a list of IP or
+//! CIDR separated by comma or whitespace, like `127.0.0.1, 10.0.0.0/8, ::1`. Restart to apply.
+use crate::db;
+use axum::async_trait;
+use axum::extract::{ConnectInfo, FromRequestParts};
+use axum::http::header::{HeaderMap, FORWARDED};
+use axum::http::request::Parts;
+use axum::http::Extensions;
+use once_cell::sync::Lazy;
+use std::convert::Infallible;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Connection info, inserted into every request by `tls::serve`.
+#[derive(Clone, Copy, Debug)]
+pub struct Peer {
+    /// The address of the TCP peer, may be a proxy.
+    pub addr: SocketAddr,
+    /// The client address, from PROXY protocol header if presents.
+    pub client: SocketAddr,
+    /// Negotiated TLS version, like `TLSv1.3`.
+    pub tls_version: &'static str,
+    /// Negotiated ALPN protocol, like `h2`.
+    pub alpn: &'static str,
+}
+
+static TRUSTED: Lazy<Vec<(IpAddr, u8)>> = Lazy::new(|| {
+    let v = db!("SELECT v FROM admin WHERE k = 'trusted_proxies'", [], ^(0));
+    let v: Vec<u8> = v.map(|v: (Vec<u8>,)| v.0).unwrap_or_default();
+    let v = String::from_utf8_lossy(&v);
+    // ignore empty or invalid items
+    v.split(|c: char| c == ',' || c.is_whitespace())
+        .filter_map(parse_net)
+        .collect()
+});
+
+/// Parse an IP or CIDR, like `10.0.0.0/8` or `::1`.
+pub fn parse_net(item: &str) -> Option<(IpAddr, u8)> {
+    let (ip, bits) = item.split_once('/').unwrap_or((item, ""));
+    let ip = ip.parse::<IpAddr>().ok()?;
+    let max = if ip.is_ipv4() { 32 } else { 128 };
+    Some((ip, bits.parse().unwrap_or(max).min(max)))
+}
+
+/// Returns `true` if the IP is in the network, IPv4-mapped IPv6 is treated as IPv4.
+pub fn net_contains((net, bits): (IpAddr, u8), ip: IpAddr) -> bool {
+    fn mask(ip: IpAddr) -> u128 {
+        match ip {
+            IpAddr::V4(v) => u32::from(v) as u128,
+            IpAddr::V6(v) => u128::from(v),
+        }
+    }
+    let ip = match ip {
+        IpAddr::V6(v) => v.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        v => v,
+    };
+    let width = if net.is_ipv4() { 32 } else { 128 };
+    let shift = width - bits as u32;
+    net.is_ipv4() == ip.is_ipv4()
+        && mask(net).checked_shr(shift).unwrap_or(0) == mask(ip).checked_shr(shift).unwrap_or(0)
+}
+
+/// Returns `true` if the IP is one of the trusted upstreams.
+pub fn is_trusted(ip: IpAddr) -> bool {
+    TRUSTED.iter().any(|&net| net_contains(net, ip))
+}
+
+/// Read the PROXY protocol (v1 or v2) header if presents, returns the source address.
+///
+/// Only consumes the header bytes, the rest of stream (such as TLS handshake) is untouched.
+///
+/// https://www.haproxy.org/download/2.6/doc/proxy-protocol.txt
+pub async fn read_proxy_header(
+    stream: &mut (impl AsyncRead + Unpin),
+    first: u8,
+) -> io::Result<Option<SocketAddr>> {
+    fn invalid() -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, "invalid PROXY protocol header")
+    }
+    const SIG_V2: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+    match first {
+        b'P' => {
+            // v1, "PROXY TCP4 192.168.0.1 192.168.0.11 56324 443\r\n", 107 bytes at most
+            let mut buf = Vec::with_capacity(107);
+            while !buf.ends_with(b"\r\n") {
+                if buf.len() >= 107 {
+                    return Err(invalid());
+                }
+                buf.push(stream.read_u8().await?);
+            }
+            let line = std::str::from_utf8(&buf).map_err(|_| invalid())?;
+            let parts = line.trim_end().split(' ').collect::<Vec<_>>();
+            match parts[..] {
+                ["PROXY", "TCP4" | "TCP6", src, _dst, sport, _dport] => {
+                    let ip = src.parse::<IpAddr>().map_err(|_| invalid())?;
+                    let port = sport.parse().map_err(|_| invalid())?;
+                    Ok(Some(SocketAddr::new(ip, port)))
+                }
+                ["PROXY", "UNKNOWN", ..] => Ok(None),
+                _ => Err(invalid()),
+            }
+        }
+        b'\r' => {
+            // v2, binary format
+            let mut head = [0; 16];
+            stream.read_exact(&mut head).await?;
+            if &head[..12] != SIG_V2 || head[12] >> 4 != 2 {
+                return Err(invalid());
+            }
+            let mut body = vec![0; u16::from_be_bytes([head[14], head[15]]) as usize];
+            stream.read_exact(&mut body).await?;
+            if head[12] & 0xf == 0 {
+                return Ok(None); // LOCAL command, such as health checks from the proxy
+            }
+            let port = |i: usize| u16::from_be_bytes([body[i], body[i + 1]]);
+            match (head[13], body.len()) {
+                (0x11, 12..) => {
+                    let ip = Ipv4Addr::from(<[u8; 4]>::try_from(&body[..4]).unwrap());
+                    Ok(Some(SocketAddr::new(ip.into(), port(8))))
+                }
+                (0x21, 36..) => {
+                    let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&body[..16]).unwrap());
+                    Ok(Some(SocketAddr::new(ip.into(), port(32))))
+                }
+                _ => Ok(None), // UNSPEC or UNIX socket
+            }
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Resolve the real client IP from connection info and forwarded headers.
+///
+/// The `Forwarded` and `X-Forwarded-For` headers are only honored if sent by trusted upstreams.
+/// The list is walked from right to left, the first untrusted address is the client.
+pub fn resolve(headers: &HeaderMap, extensions: &Extensions) -> IpAddr {
+    let mut ip = match (extensions.get::<Peer>(), extensions.get()) {
+        (Some(peer), _) => peer.client.ip(),
+        (_, Some(ConnectInfo(addr))) => SocketAddr::ip(addr),
+        _ => return Ipv4Addr::UNSPECIFIED.into(),
+    };
+    if !is_trusted(ip) {
+        return ip;
+    }
+    let forwarded = headers.get_all(FORWARDED).iter().flat_map(|v| {
+        let v = v.to_str().unwrap_or_default();
+        let v = v
+            .split([',', ';'])
+            .filter_map(|v| v.trim().strip_prefix("for="));
+        // for="[2001:db8::1]:4711", for=192.0.2.43:47011, for=unknown
+        v.map(|v| v.trim_matches('"').trim_start_matches('['))
+            .map(|v| v.rsplit_once(']').map_or(v, |v| v.0))
+            .map(|v| {
+                v.parse()
+                    .or_else(|_| v.parse::<SocketAddr>().map(|v| v.ip()))
+            })
+    });
+    let forwarded = forwarded.collect::<Vec<_>>();
+    let x_forwarded = || {
+        let v = headers.get_all("x-forwarded-for").iter();
+        let v = v.flat_map(|v| v.to_str().unwrap_or_default().split(','));
+        v.map(|v| v.trim().parse()).collect::<Vec<_>>()
+    };
+    let list = if forwarded.is_empty() {
+        x_forwarded()
+    } else {
+        forwarded
+    };
+    for item in list.into_iter().rev() {
+        match item {
+            Ok(v) if is_trusted(v) => ip = v,
+            Ok(v) => return v,
+            Err(_) => break, // obfuscated or broken, stop here
+        }
+    }
+    ip
+}
+
+/// Extractor of the real client IP.
+///
+/// # Example
+///
+/// ```
+/// MethodRouter::new().get(|ClientIp(ip): ClientIp| async move { ip.to_string() })
+/// ```
+#[derive(Clone, Copy, Debug)]
+pub struct ClientIp(pub IpAddr);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for ClientIp {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        Ok(ClientIp(resolve(&parts.headers, &parts.extensions)))
+    }
+}
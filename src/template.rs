@@ -0,0 +1,283 @@
+//! Compile-time HTML template, used by `template!` macro.
+//!
+//! The marks are block comments, so the template is still a valid html / js / css file:
+//!
+//! * `/*{name}*/`: a slot, value will be escaped.
+//! * `/*{!name}*/`: a raw slot, value will be inserted as is.
+//! * `/*{#name}*/ ... /*{/name}*/`: a section, repeated for each item.
+//!
+//! The template is parsed in const context, fill it by `Writer` in the same order as the marks.
+use std::fmt::{Display, Write};
+
+#[derive(Clone, Copy, Debug)]
+pub enum Part {
+    Text(&'static str),
+    Slot(&'static str),
+    Raw(&'static str),
+    /// The name, and the index of paired `End`.
+    Begin(&'static str, usize),
+    End,
+}
+
+pub struct Template<const N: usize>(pub [Part; N]);
+
+const OPEN: &[u8] = b"/*{";
+const CLOSE: &[u8] = b"}*/";
+
+/// Find the pattern from index, returns `s.len()` if not found.
+const fn find(s: &[u8], from: usize, pattern: &[u8]) -> usize {
+    let mut i = from;
+    while i + pattern.len() <= s.len() {
+        let mut j = 0;
+        while j < pattern.len() && s[i + j] == pattern[j] {
+            j += 1;
+        }
+        if j == pattern.len() {
+            return i;
+        }
+        i += 1;
+    }
+    s.len()
+}
+
+const fn sub(s: &'static [u8], begin: usize, end: usize) -> &'static str {
+    // this's safe, the split edges are marks which only include ASCII chars
+    unsafe {
+        let v = std::slice::from_raw_parts(s.as_ptr().add(begin), end - begin);
+        std::str::from_utf8_unchecked(v)
+    }
+}
+
+const fn str_eq(a: &str, b: &str) -> bool {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    if a.len() != b.len() {
+        return false;
+    }
+    let mut i = 0;
+    while i < a.len() {
+        if a[i] != b[i] {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+/// Count the parts, to be the length of `parse` result.
+pub const fn count(raw: &str) -> usize {
+    let s = raw.as_bytes();
+    let mut n = 1;
+    let mut i = 0;
+    loop {
+        let begin = find(s, i, OPEN);
+        if begin == s.len() {
+            return n;
+        }
+        let end = find(s, begin, CLOSE);
+        if end == s.len() {
+            panic!("template mark is not closed");
+        }
+        n += 2; // the mark and the text after it
+        i = end + CLOSE.len();
+    }
+}
+
+pub const fn parse<const N: usize>(raw: &'static str) -> [Part; N] {
+    let s = raw.as_bytes();
+    let mut ret = [Part::Text(""); N];
+    let mut stack = [0; 16];
+    let mut depth = 0;
+    let mut k = 0;
+    let mut i = 0;
+    loop {
+        let begin = find(s, i, OPEN);
+        ret[k] = Part::Text(sub(s, i, begin));
+        k += 1;
+        if begin == s.len() {
+            break;
+        }
+        let end = find(s, begin, CLOSE);
+        let name_begin = begin + OPEN.len();
+        if name_begin == end {
+            panic!("template mark has empty name");
+        }
+        let name = sub(s, name_begin + 1, end);
+        ret[k] = match s[name_begin] {
+            b'!' => Part::Raw(name),
+            b'#' => {
+                stack[depth] = k;
+                depth += 1;
+                Part::Begin(name, 0) // fill the end later
+            }
+            b'/' => {
+                if depth == 0 {
+                    panic!("template section end without begin");
+                }
+                depth -= 1;
+                match ret[stack[depth]] {
+                    Part::Begin(v, _) if str_eq(v, name) => ret[stack[depth]] = Part::Begin(v, k),
+                    _ => panic!("template section end does not match begin"),
+                }
+                Part::End
+            }
+            _ => Part::Slot(sub(s, name_begin, end)),
+        };
+        k += 1;
+        i = end + CLOSE.len();
+    }
+    if depth != 0 {
+        panic!("template section is not closed");
+    }
+    ret
+}
+
+impl<const N: usize> Template<N> {
+    pub fn writer(&'static self) -> Writer {
+        Writer {
+            parts: &self.0,
+            pos: 0,
+            out: String::new(),
+        }
+    }
+}
+
+/// Fill the template in order, panics if mismatched with the marks.
+///
+/// # Example
+///
+/// ```
+/// // <h1>/*{title}*/</h1>/*{#items}*/<p>/*{!html}*/</p>/*{/items}*/
+/// let mut o = template!("page.html").writer();
+/// o.slot("title", "Tom & Jerry");
+/// o.section("items", ["<b>1</b>", "<b>2</b>"], |o, v| {
+///     o.raw("html", v);
+/// });
+/// assert_eq!(o.finish(), "<h1>Tom &amp; Jerry</h1><p><b>1</b></p><p><b>2</b></p>");
+/// ```
+pub struct Writer {
+    parts: &'static [Part],
+    pos: usize,
+    out: String,
+}
+
+impl Writer {
+    /// Write texts until the next mark.
+    fn next(&mut self) -> Option<Part> {
+        while let Some(part) = self.parts.get(self.pos) {
+            match part {
+                Part::Text(v) => self.out += v,
+                v => return Some(*v),
+            }
+            self.pos += 1;
+        }
+        None
+    }
+
+    fn expect(&mut self, expected: &str) -> Part {
+        match self.next() {
+            Some(v @ (Part::Slot(name) | Part::Raw(name) | Part::Begin(name, _)))
+                if name == expected =>
+            {
+                v
+            }
+            v => panic!("template mark {expected} is expected, but next is {v:?}"),
+        }
+    }
+
+    /// Fill a slot with escaped value.
+    pub fn slot(&mut self, name: &str, v: impl Display) -> &mut Self {
+        match self.expect(name) {
+            Part::Slot(_) => {
+                let v = v.to_string();
+                write!(
+                    self.out,
+                    "{}",
+                    askama_escape::escape(&v, askama_escape::Html)
+                )
+                .unwrap();
+            }
+            _ => panic!("template mark {name} is not a slot"),
+        }
+        self.pos += 1;
+        self
+    }
+
+    /// Fill a raw slot, make sure the value is safe.
+    pub fn raw(&mut self, name: &str, v: impl Display) -> &mut Self {
+        match self.expect(name) {
+            Part::Raw(_) => write!(self.out, "{v}").unwrap(),
+            _ => panic!("template mark {name} is not a raw slot"),
+        }
+        self.pos += 1;
+        self
+    }
+
+    /// Repeat a section for each item, zero or one item is also fine.
+    pub fn section<T>(
+        &mut self,
+        name: &str,
+        items: impl IntoIterator<Item = T>,
+        mut f: impl FnMut(&mut Self, T),
+    ) -> &mut Self {
+        let (begin, end) = match self.expect(name) {
+            Part::Begin(_, end) => (self.pos + 1, end),
+            _ => panic!("template mark {name} is not a section"),
+        };
+        for item in items {
+            self.pos = begin;
+            f(self, item);
+            match self.next() {
+                Some(Part::End) if self.pos == end => {}
+                v => panic!("template section {name} is not completed, next is {v:?}"),
+            }
+        }
+        self.pos = end + 1;
+        self
+    }
+
+    /// Write the rest texts and returns the result.
+    pub fn finish(mut self) -> String {
+        match self.next() {
+            None => self.out,
+            v => panic!("template is not completed, next is {v:?}"),
+        }
+    }
+}
+
+/// Include and parse a template at compile time, returns `&'static Template`.
+///
+/// The content is minified by `strip_str!` in release mode.
+#[macro_export]
+macro_rules! template {
+    ($s:expr) => {{
+        use $crate::template::*;
+        const S: &str = $crate::strip_str!(include_str!($s));
+        static T: Template<{ count(S) }> = Template(parse(S));
+        &T
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static T: Template<{ count(S) }> = Template(parse(S));
+    const S: &str = "<h1>/*{title}*/</h1>/*{#items}*/<p>/*{!html}*/</p>/*{/items}*/";
+
+    #[test]
+    fn fill() {
+        let mut o = T.writer();
+        o.slot("title", "Tom & Jerry");
+        o.section("items", ["<b>1</b>", "<b>2</b>"], |o, v| {
+            o.raw("html", v);
+        });
+        let expected = "<h1>Tom &amp; Jerry</h1><p><b>1</b></p><p><b>2</b></p>";
+        assert_eq!(o.finish(), expected);
+    }
+
+    #[test]
+    #[should_panic(expected = "template mark items is expected, but next is Some(Slot(\"title\"))")]
+    fn mismatch() {
+        T.writer().section("items", [()], |_, _| {});
+    }
+}
@@ -1,9 +1,16 @@
 //! TLS & HTTPS support for the server.
-use crate::db;
+use crate::remote::{self, Peer};
+use crate::{db, limit};
+use axum::body::Body;
+use axum::extract::ws::{WebSocket, WebSocketUpgrade};
+use axum::extract::ConnectInfo;
+use axum::http::{Request, StatusCode};
+use axum::response::{IntoResponse, Response};
 use axum::routing::{IntoMakeService, Router};
 use hyper::server::accept::Accept;
-use hyper::server::conn::{AddrIncoming, Http};
-use std::future::poll_fn;
+use hyper::server::conn::{AddrIncoming, AddrStream, Http};
+use once_cell::sync::Lazy;
+use std::future::{poll_fn, Future};
 use std::mem::MaybeUninit;
 use std::net::SocketAddr;
 use std::pin::Pin;
@@ -11,9 +18,10 @@ use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::io::AsyncWriteExt;
-use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+use tokio::sync::Semaphore;
+use tokio_rustls::rustls::{Certificate, PrivateKey, ProtocolVersion, ServerConfig};
 use tokio_rustls::TlsAcceptor;
-use tower::MakeService;
+use tower::{MakeService, ServiceExt};
 
 /// Serve the services over TLS.
 ///
@@ -49,24 +57,47 @@ use tower::MakeService;
 /// * https://github.com/hyperium/hyper/blob/v0.14.20/src/server/server.rs#L176
 /// * https://github.com/tokio-rs/axum/tree/axum-v0.5.15/examples/low-level-rustls
 /// * https://github.com/programatik29/axum-server
-pub async fn serve(addr: &SocketAddr, mut app: IntoMakeService<Router>) {
+pub async fn serve(addr: &SocketAddr, app: IntoMakeService<Router>) {
+    serve_on(AddrIncoming::bind(addr).unwrap(), app).await
+}
+
+async fn serve_on(mut listener: AddrIncoming, mut app: IntoMakeService<Router>) {
     static IS_FIRST_CALL: AtomicBool = AtomicBool::new(true);
     assert!(IS_FIRST_CALL.load(Ordering::SeqCst), "called twice");
     IS_FIRST_CALL.store(false, Ordering::SeqCst);
 
-    fn db_get(k: &str) -> (Vec<u8>,) {
-        db!("SELECT v FROM admin WHERE k = ?", [k], ^(0))
-            .map_err(|_| format!("query '{k}' in table 'admin' failed"))
-            .unwrap()
+    fn db_get(k: &str) -> Option<(Vec<u8>,)> {
+        db!("SELECT v FROM admin WHERE k = ?", [k], ^(0)).ok()
+    }
+    fn db_set(k: &str, v: Vec<u8>) {
+        db!("REPLACE INTO admin VALUES (?1, ?2)", [k, v]).unwrap();
+    }
+
+    let (cert, key) = match (db_get("ssl_cert"), db_get("ssl_key")) {
+        (Some((cert,)), Some((key,))) => (cert, key),
+        _ => {
+            // first run, generate a temporary one to make `/admin` reachable
+            let generated = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+            let cert = generated.serialize_der().unwrap();
+            let key = generated.serialize_private_key_der();
+            db_set("ssl_cert", cert.clone());
+            db_set("ssl_key", key.clone());
+            db_set(TEMP_CERT_MARK, Vec::new());
+            println!("ssl cert is missing, generated a self-signed one");
+            (cert, key)
+        }
+    };
+    if db_get(TEMP_CERT_MARK).is_some() {
+        let digest = ring::digest::digest(&ring::digest::SHA256, &cert);
+        let fingerprint = digest.as_ref().iter().map(|v| format!("{v:02X}"));
+        let fingerprint = fingerprint.collect::<Vec<_>>().join(":");
+        println!("ssl cert is temporary, fingerprint (sha256) = {fingerprint}");
     }
 
     let mut tls_cfg = ServerConfig::builder()
         .with_safe_defaults()
         .with_no_client_auth()
-        .with_single_cert(
-            vec![Certificate(db_get("ssl_cert").0)],
-            PrivateKey(db_get("ssl_key").0),
-        )
+        .with_single_cert(vec![Certificate(cert)], PrivateKey(key))
         .unwrap();
     // enable http2, needs hyper feature "http2"
     tls_cfg.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
@@ -79,8 +110,6 @@ pub async fn serve(addr: &SocketAddr, mut app: IntoMakeService<Router>) {
     static TLS_ACCEPTOR: &TlsAcceptor = unsafe { _TLS_ACCEPTOR.assume_init_ref() };
     static PROTOCOL: &Http = unsafe { _PROTOCOL.assume_init_ref() };
 
-    let mut listener = AddrIncoming::bind(addr).unwrap();
-
     loop {
         let mut stream = match poll_fn(|cx| Pin::new(&mut listener).poll_accept(cx)).await {
             Some(Ok(v)) => v,
@@ -89,20 +118,66 @@ pub async fn serve(addr: &SocketAddr, mut app: IntoMakeService<Router>) {
 
         let svc = app.make_service(&stream);
         tokio::spawn(tokio::time::timeout(TIMEOUT, async move {
+            async fn peek(stream: &mut AddrStream) -> u8 {
+                let mut flag = [0];
+                let mut buf = tokio::io::ReadBuf::new(&mut flag);
+                poll_fn(|cx| stream.poll_peek(cx, &mut buf)).await.ok();
+                flag[0]
+            }
+
+            // read PROXY protocol header, only from trusted upstreams
+            let addr = stream.remote_addr();
+            let mut peer = Peer {
+                addr,
+                client: addr,
+                tls_version: "",
+                alpn: "",
+            };
+            if remote::is_trusted(addr.ip()) {
+                let first = peek(&mut stream).await;
+                match remote::read_proxy_header(&mut stream, first).await {
+                    Ok(v) => peer.client = v.unwrap_or(addr),
+                    Err(_) => return, // drop the broken connection
+                }
+            }
+
+            // per IP limits, just drop the connection because it's cheaper than responding
+            let _conn_guard = match limit::connect(peer.client.ip()) {
+                Some(v) => v,
+                None => return,
+            };
+
             // redirect HTTP to HTTPS
-            let mut flag = [0]; // expect 0x16, TLS handshake
-            let mut buf = tokio::io::ReadBuf::new(&mut flag);
-            poll_fn(|cx| stream.poll_peek(cx, &mut buf)).await.ok();
-            if flag[0] != 0x16 {
+            if peek(&mut stream).await != 0x16 {
+                // expect 0x16, TLS handshake
                 stream.write_all(TO_HTTPS_PAGE).await.ok();
                 stream.shutdown().await.ok(); // remember to close stream
                 return;
             }
 
+            if !limit::handshake(peer.client.ip()) {
+                return;
+            }
+
             if let (Ok(tls_stream), Ok(svc)) = (TLS_ACCEPTOR.accept(stream).await, svc.await) {
+                let conn = tls_stream.get_ref().1;
+                peer.tls_version = match conn.protocol_version() {
+                    Some(ProtocolVersion::TLSv1_3) => "TLSv1.3",
+                    Some(ProtocolVersion::TLSv1_2) => "TLSv1.2",
+                    _ => "unknown",
+                };
+                peer.alpn = match conn.alpn_protocol() {
+                    Some(b"h2") => "h2",
+                    _ => "http/1.1",
+                };
+                let svc = svc.map_request(move |mut req: Request<Body>| {
+                    req.extensions_mut().insert(peer);
+                    req.extensions_mut().insert(ConnectInfo(peer.client));
+                    req
+                });
                 PROTOCOL
                     .serve_connection(tls_stream, svc)
-                    // .with_upgrades() // allow WebSocket
+                    .with_upgrades() // allow WebSocket, see `upgrade()`
                     .await
                     .ok();
             }
@@ -110,8 +185,160 @@ pub async fn serve(addr: &SocketAddr, mut app: IntoMakeService<Router>) {
     }
 }
 
+/// Accept a WebSocket upgrade if the upgraded connections limit is not reached.
+///
+/// An upgraded connection was handed off from `serve_connection`, so it's not covered by the
+/// `TIMEOUT` above. Use this instead of `WebSocketUpgrade::on_upgrade` to keep them bounded.
+///
+/// # Example
+///
+/// ```
+/// MethodRouter::new().get(|u: WebSocketUpgrade| async {
+///     tls::upgrade(u, |mut ws| async move {
+///         while let Some(Ok(_)) = ws.recv().await {}
+///     })
+/// })
+/// ```
+pub fn upgrade<F, Fut>(u: WebSocketUpgrade, callback: F) -> Response
+where
+    F: FnOnce(WebSocket) -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    upgrade_limited(u, UPGRADED_MESSAGE_LIMIT, callback)
+}
+
+/// The same as `upgrade`, with a smaller message size limit in bytes.
+pub fn upgrade_limited<F, Fut>(
+    u: WebSocketUpgrade,
+    max_message_size: usize,
+    callback: F,
+) -> Response
+where
+    F: FnOnce(WebSocket) -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    static UPGRADED: Lazy<Semaphore> = Lazy::new(|| Semaphore::new(UPGRADED_LIMIT));
+    let permit = match UPGRADED.try_acquire() {
+        Ok(v) => v,
+        Err(_) => return (StatusCode::SERVICE_UNAVAILABLE, "too many upgraded").into_response(),
+    };
+    u.max_message_size(max_message_size.min(UPGRADED_MESSAGE_LIMIT))
+        .on_upgrade(|ws| async move {
+            callback(ws).await;
+            drop(permit); // release after the socket closed
+        })
+        .into_response()
+}
+
+/// The key in `admin` table, exists if the `ssl_cert` is a generated temporary one.
+pub const TEMP_CERT_MARK: &str = "ssl_temp";
+
 // https://nginx.org/en/docs/http/ngx_http_core_module.html#keepalive_timeout
 const TIMEOUT: Duration = Duration::from_secs(75);
 
+const UPGRADED_LIMIT: usize = 256;
+const UPGRADED_MESSAGE_LIMIT: usize = 1024 * 1024;
+
 const TO_HTTPS_PAGE: &[u8] = b"HTTP/1.1 200 OK\r\ncontent-type:text/html\r\n\r\n\
 <script>location=location.href.replace(':','s:')</script>\r\n\r\n\0";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::routing::get;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpStream;
+    use tokio_rustls::client::TlsStream;
+    use tokio_rustls::rustls::{ClientConfig, RootCertStore, ServerName};
+    use tokio_rustls::TlsConnector;
+
+    async fn echo(mut ws: WebSocket) {
+        while let Some(Ok(msg)) = ws.recv().await {
+            if ws.send(msg).await.is_err() {
+                break;
+            }
+        }
+    }
+
+    /// Connect and request upgrade, returns the status code.
+    async fn connect(addr: SocketAddr, tls: &TlsConnector) -> (u16, TlsStream<TcpStream>) {
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let name = ServerName::try_from("localhost").unwrap();
+        let mut stream = tls.connect(name, stream).await.unwrap();
+        let req = "GET /ws HTTP/1.1\r\nhost: localhost\r\nconnection: upgrade\r\n\
+            upgrade: websocket\r\nsec-websocket-version: 13\r\n\
+            sec-websocket-key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n";
+        stream.write_all(req.as_bytes()).await.unwrap();
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            head.push(stream.read_u8().await.unwrap());
+        }
+        let head = String::from_utf8(head).unwrap();
+        (head[9..12].parse().unwrap(), stream)
+    }
+
+    /// A masked text frame from client, the payload should be shorter than 126 bytes.
+    async fn send_text(stream: &mut TlsStream<TcpStream>, text: &str) {
+        let mask = [1, 2, 3, 4];
+        let mut frame = vec![0x81, 0x80 | text.len() as u8];
+        frame.extend(mask);
+        frame.extend(text.bytes().enumerate().map(|(i, v)| v ^ mask[i % 4]));
+        stream.write_all(&frame).await.unwrap();
+    }
+
+    async fn recv_text(stream: &mut TlsStream<TcpStream>) -> String {
+        let mut head = [0; 2];
+        stream.read_exact(&mut head).await.unwrap();
+        assert_eq!(head[0], 0x81);
+        let mut payload = vec![0; head[1] as usize];
+        stream.read_exact(&mut payload).await.unwrap();
+        String::from_utf8(payload).unwrap()
+    }
+
+    #[tokio::test]
+    async fn websocket() {
+        db!("CREATE TABLE IF NOT EXISTS admin (k TEXT PRIMARY KEY, v BLOB)").unwrap();
+        limit::set_cfg("conn", 0.0, 1024.0);
+        limit::set_cfg("handshake", 1024.0, 1024.0);
+        let listener = AddrIncoming::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).unwrap();
+        let addr = listener.local_addr();
+        let app = Router::new().route("/ws", get(|u: WebSocketUpgrade| async { upgrade(u, echo) }));
+        tokio::spawn(serve_on(listener, app.into_make_service()));
+
+        // the cert is generated by the first call
+        let cert = loop {
+            match db!("SELECT v FROM admin WHERE k = 'ssl_cert'", [], ^(0)) {
+                Ok((v,)) => break v,
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        };
+        let mut roots = RootCertStore::empty();
+        roots.add(&Certificate(cert)).unwrap();
+        let tls_cfg = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let tls = TlsConnector::from(Arc::new(tls_cfg));
+
+        let (status, mut stream) = connect(addr, &tls).await;
+        assert_eq!(status, 101);
+        send_text(&mut stream, "hello").await;
+        assert_eq!(recv_text(&mut stream).await, "hello");
+
+        let mut upgraded = vec![stream];
+        while upgraded.len() < UPGRADED_LIMIT {
+            let (status, stream) = connect(addr, &tls).await;
+            assert_eq!(status, 101);
+            upgraded.push(stream);
+        }
+        assert_eq!(connect(addr, &tls).await.0, 503);
+
+        // released after the socket closed
+        drop(upgraded.pop());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let (status, mut stream) = connect(addr, &tls).await;
+        assert_eq!(status, 101);
+        send_text(&mut stream, "again").await;
+        assert_eq!(recv_text(&mut stream).await, "again");
+    }
+}
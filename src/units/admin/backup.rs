@@ -0,0 +1,650 @@
+//! Database backup and restore.
+//!
+//! A backup is a tar with the database snapshot and the `paste_next` storage directory,
+//! optionally compressed by gzip, then optionally encrypted:
+//!
+//! * `database.db`: made by `VACUUM INTO`, a consistent snapshot.
+//! * `data/paste/storage/*`: the files of `paste_next`, if exists.
+//!
+//! The encrypted format is `MAGIC | salt(16) | chunks`. Every chunk is 64 KiB plaintext sealed by
+//! AES-256-GCM with the key from PBKDF2-HMAC-SHA256, the last chunk is flagged in its nonce to
+//! detect truncation.
+use crate::database;
+use crate::db;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use futures_core::Stream;
+use hyper::body::Bytes;
+use rand::{thread_rng, RngCore};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM};
+use ring::pbkdf2::{self, PBKDF2_HMAC_SHA256};
+use rusqlite::Connection;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, Write};
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::UNIX_EPOCH;
+use tokio::sync::mpsc;
+
+const MAGIC: &[u8; 8] = b"KSITEENC";
+const CHUNK: usize = 64 * 1024;
+const TAG_LEN: usize = 16;
+const PBKDF2_ROUNDS: u32 = 100_000;
+/// Count of scheduled backups to keep.
+const KEEP: usize = 7;
+const DB_NAME: &str = "database.db";
+const STORAGE_NAME: &str = "data/paste/storage/";
+
+fn error(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn data_dir() -> PathBuf {
+    std::env::current_exe().unwrap().with_file_name("data")
+}
+
+/// Same as the `STORAGE_ROOT` of `paste_next`.
+fn storage_dir() -> PathBuf {
+    data_dir().join("paste").join("storage")
+}
+
+/// The scheduled backups and temporary files, in the same file system with database.
+fn backup_dir() -> io::Result<PathBuf> {
+    let p = data_dir().join("backup");
+    fs::create_dir_all(&p)?;
+    Ok(p)
+}
+
+/// A random temporary file path in the backup directory.
+pub fn temp_path(tag: &str) -> io::Result<PathBuf> {
+    Ok(backup_dir()?.join(format!(".{tag}-{}", thread_rng().next_u64())))
+}
+
+fn now() -> u64 {
+    UNIX_EPOCH.elapsed().unwrap().as_secs()
+}
+
+fn derive_key(password: &str, salt: &[u8]) -> LessSafeKey {
+    let mut key = [0; 32];
+    let rounds = NonZeroU32::new(PBKDF2_ROUNDS).unwrap();
+    pbkdf2::derive(
+        PBKDF2_HMAC_SHA256,
+        rounds,
+        salt,
+        password.as_bytes(),
+        &mut key,
+    );
+    LessSafeKey::new(UnboundKey::new(&AES_256_GCM, &key).unwrap())
+}
+
+fn nonce(counter: u64, last: bool) -> Nonce {
+    let mut v = [0; 12];
+    v[0] = last as u8;
+    v[4..].copy_from_slice(&counter.to_be_bytes());
+    Nonce::assume_unique_for_key(v)
+}
+
+/// Encrypt into chunks, call `finish` at the end.
+struct Seal<W: Write> {
+    inner: W,
+    key: LessSafeKey,
+    buf: Vec<u8>,
+    counter: u64,
+}
+
+impl<W: Write> Seal<W> {
+    fn new(mut inner: W, password: &str) -> io::Result<Self> {
+        let mut salt = [0; 16];
+        thread_rng().fill_bytes(&mut salt);
+        inner.write_all(MAGIC)?;
+        inner.write_all(&salt)?;
+        Ok(Self {
+            inner,
+            key: derive_key(password, &salt),
+            buf: Vec::new(),
+            counter: 0,
+        })
+    }
+
+    fn seal(&mut self, last: bool) -> io::Result<()> {
+        let n = self.buf.len().min(CHUNK);
+        let mut chunk = self.buf.drain(..n).collect::<Vec<_>>();
+        let nonce = nonce(self.counter, last);
+        let ret = self
+            .key
+            .seal_in_place_append_tag(nonce, Aad::empty(), &mut chunk);
+        ret.map_err(|_| error("encrypt failed"))?;
+        self.counter += 1;
+        self.inner.write_all(&chunk)
+    }
+
+    fn finish(mut self) -> io::Result<W> {
+        self.seal(true)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for Seal<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        while self.buf.len() > CHUNK {
+            self.seal(false)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Read until the buffer is full or EOF, returns the bytes read.
+fn read_block(r: &mut impl Read, len: usize) -> io::Result<Vec<u8>> {
+    let mut v = Vec::with_capacity(len);
+    r.take(len as u64).read_to_end(&mut v)?;
+    Ok(v)
+}
+
+/// Decrypt the chunks made by `Seal`, the `MAGIC` should be consumed before.
+struct Open<R: Read> {
+    inner: R,
+    key: LessSafeKey,
+    counter: u64,
+    /// The next sealed chunk, read ahead to know if the current one is the last.
+    next: Option<Vec<u8>>,
+    out: Vec<u8>,
+    pos: usize,
+    done: bool,
+}
+
+impl<R: Read> Open<R> {
+    fn new(mut inner: R, password: &str) -> io::Result<Self> {
+        let salt = read_block(&mut inner, 16)?;
+        Ok(Self {
+            inner,
+            key: derive_key(password, &salt),
+            counter: 0,
+            next: None,
+            out: Vec::new(),
+            pos: 0,
+            done: false,
+        })
+    }
+
+    fn refill(&mut self) -> io::Result<()> {
+        let mut chunk = match self.next.take() {
+            Some(v) => v,
+            None => read_block(&mut self.inner, CHUNK + TAG_LEN)?,
+        };
+        let next = read_block(&mut self.inner, CHUNK + TAG_LEN)?;
+        let last = next.is_empty();
+        let nonce = nonce(self.counter, last);
+        let ret = self.key.open_in_place(nonce, Aad::empty(), &mut chunk);
+        let n = ret
+            .map_err(|_| error("decrypt failed, wrong password or broken file"))?
+            .len();
+        chunk.truncate(n);
+        self.counter += 1;
+        (self.out, self.pos, self.done) = (chunk, 0, last);
+        self.next = Some(next).filter(|_| !last);
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for Open<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.out.len() {
+            if self.done {
+                return Ok(0);
+            }
+            self.refill()?;
+        }
+        let n = buf.len().min(self.out.len() - self.pos);
+        buf[..n].copy_from_slice(&self.out[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// The ustar header, only regular files and directories are used.
+fn tar_header(name: &str, size: u64, mtime: u64, dir: bool) -> io::Result<[u8; 512]> {
+    if name.len() >= 100 {
+        return Err(error("file name too long"));
+    }
+    let mut h = [0; 512];
+    let mut put = |offset: usize, v: &[u8]| h[offset..offset + v.len()].copy_from_slice(v);
+    put(0, name.as_bytes());
+    put(100, if dir { b"0000755" } else { b"0000644" });
+    put(108, b"0000000"); // uid
+    put(116, b"0000000"); // gid
+    put(124, format!("{size:011o}").as_bytes());
+    put(136, format!("{mtime:011o}").as_bytes());
+    put(148, b"        "); // checksum is calculated with spaces
+    put(156, if dir { b"5" } else { b"0" });
+    put(257, b"ustar\x0000");
+    let sum = h.iter().map(|&v| v as u32).sum::<u32>();
+    h[148..156].copy_from_slice(format!("{sum:06o}\0 ").as_bytes());
+    Ok(h)
+}
+
+fn tar_file(w: &mut impl Write, name: &str, path: &Path) -> io::Result<()> {
+    let mut file = File::open(path)?;
+    let meta = file.metadata()?;
+    let mtime = meta
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    w.write_all(&tar_header(name, meta.len(), mtime.as_secs(), false)?)?;
+    // the file may grow while reading, keep the size in header
+    let n = io::copy(&mut (&mut file).take(meta.len()), w)?;
+    if n != meta.len() {
+        return Err(error("file changed while reading"));
+    }
+    w.write_all(&[0; 512][..(512 - n as usize % 512) % 512])
+}
+
+/// Write the tar of database snapshot and storage.
+fn write_tar(mut w: impl Write) -> io::Result<()> {
+    let snapshot = temp_path("snapshot")?;
+    let ret = db!("VACUUM INTO ?", [snapshot.to_str().unwrap()]);
+    ret.map_err(|e| error(&e.to_string()))?;
+    let ret = tar_file(&mut w, DB_NAME, &snapshot);
+    fs::remove_file(&snapshot)?;
+    ret?;
+    if let Ok(entries) = fs::read_dir(storage_dir()) {
+        w.write_all(&tar_header(STORAGE_NAME, 0, now(), true)?)?;
+        for entry in entries {
+            let entry = entry?;
+            if entry.file_type()?.is_file() {
+                let name = format!("{STORAGE_NAME}{}", entry.file_name().to_string_lossy());
+                tar_file(&mut w, &name, &entry.path())?;
+            }
+        }
+    }
+    w.write_all(&[0; 1024])?; // the end-of-archive marker
+    w.flush()
+}
+
+/// Read the tar, call `f` with name, is directory and content of each entry.
+fn read_tar(
+    mut r: impl Read,
+    mut f: impl FnMut(&str, bool, &mut dyn Read) -> io::Result<()>,
+) -> io::Result<()> {
+    fn field(v: &[u8]) -> &str {
+        let v = v.split(|&b| b == 0).next().unwrap();
+        std::str::from_utf8(v).unwrap_or_default().trim()
+    }
+    let mut h = [0; 512];
+    loop {
+        r.read_exact(&mut h)?;
+        if h.iter().all(|&v| v == 0) {
+            return Ok(());
+        }
+        let name = match &h[257..262] == b"ustar" && h[345] != 0 {
+            true => format!("{}/{}", field(&h[345..500]), field(&h[..100])),
+            false => field(&h[..100]).to_string(),
+        };
+        let size = u64::from_str_radix(field(&h[124..136]), 8).map_err(|_| error("bad tar"))?;
+        let mut content = (&mut r).take(size);
+        f(&name, h[156] == b'5', &mut content)?;
+        io::copy(&mut content, &mut io::sink())?; // skip the rest
+        let padding = (512 - size % 512) % 512;
+        io::copy(&mut (&mut r).take(padding), &mut io::sink())?;
+    }
+}
+
+/// Write a backup, the `w` should be buffered.
+pub fn export(w: impl Write, gzip: bool, password: Option<&str>) -> io::Result<()> {
+    fn plain(w: impl Write, gzip: bool) -> io::Result<()> {
+        match gzip {
+            true => {
+                let mut encoder = GzEncoder::new(w, Compression::default());
+                write_tar(&mut encoder)?;
+                encoder.finish()?.flush()
+            }
+            false => write_tar(w),
+        }
+    }
+    match password {
+        Some(password) => {
+            let mut seal = Seal::new(w, password)?;
+            plain(&mut seal, gzip)?;
+            seal.finish()?;
+            Ok(())
+        }
+        None => plain(w, gzip),
+    }
+}
+
+struct Schema {
+    /// The name and the `CREATE` statement of tables.
+    tables: Vec<(String, String)>,
+    /// The applied migrations, see `database::migrate`.
+    migrations: Vec<String>,
+}
+
+/// The schema of uploaded database, see `validate` for the current one.
+fn schema(conn: &Connection) -> rusqlite::Result<Schema> {
+    let sql = "SELECT name, sql FROM sqlite_master WHERE type = 'table' ORDER BY name";
+    let mut stmt = conn.prepare(sql)?;
+    let tables = stmt.query_map([], |r| Ok((r.get(0)?, r.get(1)?)))?;
+    let tables = tables.collect::<Result<Vec<_>, _>>()?;
+    // not exists in the databases before the first migration
+    let migrations = match conn.prepare("SELECT name FROM migrations") {
+        Ok(mut stmt) => {
+            let rows = stmt.query_map([], |r| r.get(0))?;
+            rows.collect::<Result<Vec<_>, _>>()?
+        }
+        Err(_) => Vec::new(),
+    };
+    Ok(Schema { tables, migrations })
+}
+
+/// Check the integrity, and the schema should be the same as current one.
+fn validate(path: &Path) -> Result<(), String> {
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    let ret: String = { conn.query_row("PRAGMA integrity_check", [], |r| r.get(0)) }
+        .map_err(|e| e.to_string())?;
+    if ret != "ok" {
+        return Err(format!("integrity check failed: {ret}"));
+    }
+    let upload = schema(&conn).map_err(|e| e.to_string())?;
+    let current = Schema {
+        tables: db! {"
+            SELECT name, sql FROM sqlite_master
+            WHERE type = 'table' ORDER BY name
+        ", [], (0, 1)}
+        .map_err(|e| e.to_string())?,
+        migrations: db!("SELECT name FROM migrations", [], (0))
+            .unwrap_or_default()
+            .into_iter()
+            .map(|v: (String,)| v.0)
+            .collect(),
+    };
+    // the missing ones are applied after restart, the unknown ones are from a newer version
+    for name in &upload.migrations {
+        if !current.migrations.contains(name) {
+            return Err(format!("migration {name} is unknown, the backup is newer"));
+        }
+    }
+    // the tables only in one side are fine, units create them lazily
+    for (name, sql) in &upload.tables {
+        let table = current.tables.iter().find(|v| &v.0 == name);
+        if matches!(table, Some((_, v)) if v != sql) {
+            return Err(format!("schema of table {name} mismatched"));
+        }
+    }
+    Ok(())
+}
+
+/// Extract and validate a backup file, returns if has storage and the count of storage files.
+fn unpack(
+    path: &Path,
+    password: Option<&str>,
+    new_db: &Path,
+    new_storage: &Path,
+) -> Result<(bool, usize), String> {
+    fs::remove_file(new_db).ok();
+    fs::remove_dir_all(new_storage).ok();
+
+    let mut has_storage = false;
+    let mut files = 0;
+    let ret = (|| {
+        let mut file = File::open(path)?;
+        let encrypted = read_block(&mut file, MAGIC.len())? == MAGIC;
+        let r: Box<dyn Read> = match (encrypted, password) {
+            (true, Some(password)) => Box::new(Open::new(file, password)?),
+            (true, None) => return Err(error("encrypted, password is required")),
+            (false, _) => {
+                file.rewind()?;
+                Box::new(file)
+            }
+        };
+        let mut r = BufReader::new(r);
+        let r: Box<dyn Read> = match r.fill_buf()?.starts_with(&[0x1f, 0x8b]) {
+            true => Box::new(GzDecoder::new(r)),
+            false => Box::new(r),
+        };
+        read_tar(r, |name, dir, content| {
+            match (name.strip_prefix(STORAGE_NAME), dir) {
+                _ if name == DB_NAME => {
+                    io::copy(content, &mut File::create(new_db)?)?;
+                }
+                (Some(""), true) => {
+                    fs::create_dir_all(new_storage)?;
+                    has_storage = true;
+                }
+                // the file names of storage are numbers
+                (Some(v), false) if has_storage && v.bytes().all(|b| b.is_ascii_digit()) => {
+                    io::copy(content, &mut File::create(new_storage.join(v))?)?;
+                    files += 1;
+                }
+                _ => return Err(error(&format!("unexpected entry {name}"))),
+            }
+            Ok(())
+        })
+    })();
+    let ret = ret
+        .map_err(|e| e.to_string())
+        .and_then(|_| match new_db.exists() {
+            true => validate(new_db),
+            false => Err("database is missing".to_string()),
+        });
+    if let Err(e) = ret {
+        fs::remove_file(new_db).ok();
+        fs::remove_dir_all(new_storage).ok();
+        return Err(e);
+    }
+    Ok((has_storage, files))
+}
+
+/// Validate and restore a backup file, returns the summary.
+pub fn import(path: &Path, password: Option<&str>) -> Result<String, String> {
+    let dir = backup_dir().map_err(|e| e.to_string())?;
+    let new_db = dir.join(".restore.db");
+    let new_storage = dir.join(".restore-storage");
+    let (has_storage, files) = unpack(path, password, &new_db, &new_storage)?;
+
+    database::replace(&new_db).map_err(|e| e.to_string())?;
+    if has_storage {
+        let storage = storage_dir();
+        let old = dir.join(".old-storage");
+        fs::remove_dir_all(&old).ok();
+        fs::create_dir_all(&storage).map_err(|e| e.to_string())?;
+        fs::rename(&storage, &old).map_err(|e| e.to_string())?;
+        fs::rename(&new_storage, &storage).map_err(|e| e.to_string())?;
+        fs::remove_dir_all(&old).ok();
+    }
+    Ok(format!(
+        "restored database and {files} storage files, restart to apply all configs"
+    ))
+}
+
+/// Send the written bytes to a channel, for streaming response.
+struct ChannelWriter(mpsc::Sender<io::Result<Bytes>>);
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let ret = self.0.blocking_send(Ok(Bytes::copy_from_slice(buf)));
+        ret.map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "receiver dropped"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+pub struct ReceiverStream(mpsc::Receiver<io::Result<Bytes>>);
+
+impl Stream for ReceiverStream {
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.0.poll_recv(cx)
+    }
+}
+
+/// Export in a blocking thread, the error is sent as the last item to abort the response.
+pub fn export_stream(gzip: bool, password: Option<String>) -> ReceiverStream {
+    let (tx, rx) = mpsc::channel(4);
+    tokio::task::spawn_blocking(move || {
+        let w = BufWriter::with_capacity(CHUNK, ChannelWriter(tx.clone()));
+        if let Err(e) = export(w, gzip, password.as_deref()) {
+            tx.blocking_send(Err(e)).ok();
+        }
+    });
+    ReceiverStream(rx)
+}
+
+/// Make a gzip backup in local directory, keeps the latest `KEEP` ones.
+pub fn scheduled() -> io::Result<()> {
+    let dir = backup_dir()?;
+    let path = dir.join(format!("ksite-{}.tar.gz", now()));
+    let tmp = path.with_extension("tmp");
+    export(BufWriter::new(File::create(&tmp)?), true, None)?;
+    fs::rename(&tmp, &path)?;
+    let mut names = fs::read_dir(&dir)?
+        .filter_map(|v| v.ok()?.file_name().into_string().ok())
+        .filter(|v| v.starts_with("ksite-") && v.ends_with(".tar.gz"))
+        .collect::<Vec<_>>();
+    // the timestamps have the same length for centuries
+    names.sort_unstable();
+    for name in names.iter().rev().skip(KEEP) {
+        fs::remove_file(dir.join(name))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Export into a temporary file, and unpack it like `import` without replacing database.
+    struct Case {
+        file: PathBuf,
+        new_db: PathBuf,
+        new_storage: PathBuf,
+    }
+
+    impl Case {
+        fn new() -> Self {
+            Case {
+                file: temp_path("test").unwrap(),
+                new_db: temp_path("test-db").unwrap(),
+                new_storage: temp_path("test-storage").unwrap(),
+            }
+        }
+
+        fn export(&self, gzip: bool, password: Option<&str>) -> Vec<u8> {
+            let w = BufWriter::new(File::create(&self.file).unwrap());
+            export(w, gzip, password).unwrap();
+            fs::read(&self.file).unwrap()
+        }
+
+        fn unpack(&self, password: Option<&str>) -> Result<(bool, usize), String> {
+            unpack(&self.file, password, &self.new_db, &self.new_storage)
+        }
+    }
+
+    impl Drop for Case {
+        fn drop(&mut self) {
+            fs::remove_file(&self.file).ok();
+            fs::remove_file(&self.new_db).ok();
+            fs::remove_dir_all(&self.new_storage).ok();
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        db!("CREATE TABLE IF NOT EXISTS backup_test (v TEXT)").unwrap();
+        db!("INSERT INTO backup_test VALUES ('round trip')").unwrap();
+        let case = Case::new();
+        let check = |password| {
+            case.unpack(password).unwrap();
+            let conn = Connection::open(&case.new_db).unwrap();
+            let sql = "SELECT count(*) FROM backup_test WHERE v = 'round trip'";
+            let n: i64 = conn.query_row(sql, [], |r| r.get(0)).unwrap();
+            assert!(n > 0);
+        };
+
+        let v = case.export(false, None);
+        assert_eq!(&v[..DB_NAME.len()], DB_NAME.as_bytes());
+        assert!(v.ends_with(&[0; 1024]));
+        check(None);
+
+        let v = case.export(true, None);
+        assert!(v.starts_with(&[0x1f, 0x8b]));
+        check(None);
+
+        let v = case.export(true, Some("pw"));
+        assert!(v.starts_with(MAGIC));
+        check(Some("pw"));
+        let e = case.unpack(None).unwrap_err();
+        assert_eq!(e, "encrypted, password is required");
+        let e = case.unpack(Some("wrong")).unwrap_err();
+        assert!(e.starts_with("decrypt failed"));
+        assert!(!case.new_db.exists());
+
+        // truncated
+        fs::write(&case.file, &v[..v.len() - 1]).unwrap();
+        assert!(case.unpack(Some("pw")).is_err());
+        let len = MAGIC.len() + 16 + (v.len() - MAGIC.len() - 16) / 2;
+        fs::write(&case.file, &v[..len]).unwrap();
+        assert!(case.unpack(Some("pw")).is_err());
+    }
+
+    #[test]
+    fn seal() {
+        let data = (0..CHUNK * 2 + 100).map(|v| v as u8).collect::<Vec<_>>();
+        let mut seal = Seal::new(Vec::new(), "pw").unwrap();
+        seal.write_all(&data).unwrap();
+        let v = seal.finish().unwrap();
+        let open = |v: &[u8], password| {
+            let mut r = Open::new(&v[MAGIC.len()..], password)?;
+            let mut out = Vec::new();
+            r.read_to_end(&mut out).map(|_| out)
+        };
+        assert_eq!(open(&v, "pw").unwrap(), data);
+        assert!(open(&v, "wrong").is_err());
+        // cut at the chunk boundaries, which are valid chunks but not the last
+        for n in 1..=2 {
+            let len = MAGIC.len() + 16 + (CHUNK + TAG_LEN) * n;
+            assert!(open(&v[..len], "pw").is_err());
+        }
+    }
+
+    #[test]
+    fn invalid() {
+        let case = Case::new();
+
+        // the database is missing
+        let mut w = File::create(&case.file).unwrap();
+        w.write_all(&tar_header(STORAGE_NAME, 0, 0, true).unwrap())
+            .unwrap();
+        w.write_all(&[0; 1024]).unwrap();
+        assert_eq!(case.unpack(None).unwrap_err(), "database is missing");
+
+        // from a newer version
+        let path = temp_path("test-newer").unwrap();
+        let conn = Connection::open(&path).unwrap();
+        conn.execute_batch(
+            "
+            CREATE TABLE migrations (name TEXT PRIMARY KEY, time INTEGER);
+            INSERT INTO migrations VALUES ('from_future', 0);
+            ",
+        )
+        .unwrap();
+        drop(conn);
+        let mut w = File::create(&case.file).unwrap();
+        tar_file(&mut w, DB_NAME, &path).unwrap();
+        w.write_all(&[0; 1024]).unwrap();
+        fs::remove_file(&path).unwrap();
+        let e = case.unpack(None).unwrap_err();
+        assert_eq!(e, "migration from_future is unknown, the backup is newer");
+    }
+}
@@ -0,0 +1,233 @@
+//! Key/value entries in `admin` and `qqbot_cfg` tables, with typed editors.
+//!
+//! The last modified time is recorded by triggers into `admin_kv_time` table, so other units
+//! can keep writing the tables directly.
+use crate::db;
+use crate::remote;
+use crate::tls::TEMP_CERT_MARK;
+use crate::units::qqbot;
+use crate::utils::{base64_decode, base64_encode};
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+use tokio_rustls::rustls::{sign, PrivateKey};
+
+fn db_init() {
+    // created by qqbot lazily, create here to make triggers valid
+    db! {"
+        CREATE TABLE IF NOT EXISTS qqbot_cfg
+        (k TEXT PRIMARY KEY, v BLOB)
+    "}
+    .unwrap();
+    db! {"
+        CREATE TABLE IF NOT EXISTS admin_kv_time
+        (tbl TEXT, k TEXT, time INTEGER, PRIMARY KEY (tbl, k))
+    "}
+    .unwrap();
+    // `REPLACE INTO` fires the insert trigger, it's a deletion and an insertion
+    db! {"
+        CREATE TRIGGER IF NOT EXISTS admin_kv_time_admin_insert
+        AFTER INSERT ON admin BEGIN
+            REPLACE INTO admin_kv_time VALUES ('admin', NEW.k, strftime('%s','now'));
+        END
+    "}
+    .unwrap();
+    db! {"
+        CREATE TRIGGER IF NOT EXISTS admin_kv_time_admin_update
+        AFTER UPDATE ON admin BEGIN
+            REPLACE INTO admin_kv_time VALUES ('admin', NEW.k, strftime('%s','now'));
+        END
+    "}
+    .unwrap();
+    db! {"
+        CREATE TRIGGER IF NOT EXISTS admin_kv_time_qqbot_cfg_insert
+        AFTER INSERT ON qqbot_cfg BEGIN
+            REPLACE INTO admin_kv_time VALUES ('qqbot_cfg', NEW.k, strftime('%s','now'));
+        END
+    "}
+    .unwrap();
+    db! {"
+        CREATE TRIGGER IF NOT EXISTS admin_kv_time_qqbot_cfg_update
+        AFTER UPDATE ON qqbot_cfg BEGIN
+            REPLACE INTO admin_kv_time VALUES ('qqbot_cfg', NEW.k, strftime('%s','now'));
+        END
+    "}
+    .unwrap();
+}
+fn db_list() -> Vec<(String, String, i64, Option<i64>)> {
+    // the time is unknown for entries written before the triggers
+    db! {"
+        SELECT 'admin', a.k, length(CAST(a.v AS BLOB)), t.time FROM admin a
+        LEFT JOIN admin_kv_time t ON t.tbl = 'admin' AND t.k = a.k
+        UNION ALL
+        SELECT 'qqbot_cfg', q.k, length(CAST(q.v AS BLOB)), t.time FROM qqbot_cfg q
+        LEFT JOIN admin_kv_time t ON t.tbl = 'qqbot_cfg' AND t.k = q.k
+        ORDER BY 1, 2
+    ", [], (0, 1, 2, 3)}
+    .unwrap()
+}
+fn db_get(table: Table, k: &str) -> Option<(Vec<u8>,)> {
+    match table {
+        Table::Admin => db!("SELECT v FROM admin WHERE k = ?", [k], ^(0)),
+        Table::QqbotCfg => db!("SELECT v FROM qqbot_cfg WHERE k = ?", [k], ^(0)),
+    }
+    .ok()
+}
+fn db_set(table: Table, k: &str, v: Vec<u8>) {
+    match table {
+        Table::Admin => db!("REPLACE INTO admin VALUES (?1, ?2)", [k, v]),
+        Table::QqbotCfg => db!("REPLACE INTO qqbot_cfg VALUES (?1, ?2)", [k, v]),
+    }
+    .unwrap();
+}
+fn db_delete(table: Table, k: &str) -> bool {
+    let n = match table {
+        Table::Admin => db!("DELETE FROM admin WHERE k = ?", [k]),
+        Table::QqbotCfg => db!("DELETE FROM qqbot_cfg WHERE k = ?", [k]),
+    };
+    db! {"
+        DELETE FROM admin_kv_time
+        WHERE tbl = ?1 AND k = ?2
+    ", [table.name(), k]}
+    .unwrap();
+    n.unwrap() > 0
+}
+
+pub fn init() {
+    db_init();
+}
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Table {
+    Admin,
+    QqbotCfg,
+}
+
+impl Table {
+    fn name(self) -> &'static str {
+        match self {
+            Table::Admin => "admin",
+            Table::QqbotCfg => "qqbot_cfg",
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct Entry {
+    table: String,
+    k: String,
+    /// In bytes.
+    size: i64,
+    /// Unix timestamp in seconds, `None` if unknown.
+    time: Option<i64>,
+}
+
+pub fn list() -> Vec<Entry> {
+    let entries = db_list().into_iter();
+    let entries = entries.map(|(table, k, size, time)| Entry {
+        table,
+        k,
+        size,
+        time,
+    });
+    entries.collect()
+}
+
+/// The raw value, for downloading.
+pub fn get(table: Table, k: &str) -> Option<Vec<u8>> {
+    db_get(table, k).map(|v| v.0)
+}
+
+pub fn delete(table: Table, k: &str) -> bool {
+    db_delete(table, k)
+}
+
+/// The PEM label of a DER value, certificates and keys are stored as DER.
+fn pem_label(table: Table, k: &str) -> Option<&'static str> {
+    match (table, k) {
+        (Table::Admin, "ssl_cert") => Some("CERTIFICATE"),
+        (Table::Admin, "ssl_key") => Some("PRIVATE KEY"),
+        _ => None,
+    }
+}
+
+/// The value in editable text, DER values are encoded as PEM.
+pub fn get_text(table: Table, k: &str) -> Result<Option<String>> {
+    let v = match get(table, k) {
+        Some(v) => v,
+        None => return Ok(None),
+    };
+    if let Some(label) = pem_label(table, k) {
+        let mut o = format!("-----BEGIN {label}-----\n");
+        for line in base64_encode(&v).as_bytes().chunks(64) {
+            o += std::str::from_utf8(line).unwrap();
+            o += "\n";
+        }
+        o += &format!("-----END {label}-----\n");
+        return Ok(Some(o));
+    }
+    match String::from_utf8(v) {
+        Ok(v) => Ok(Some(v)),
+        Err(_) => bail!("binary value, download it instead"),
+    }
+}
+
+/// Decode the first PEM block, returns the label and the DER.
+fn pem_decode(text: &str) -> Result<(&str, Vec<u8>)> {
+    let text = text.trim_start();
+    let label = text
+        .strip_prefix("-----BEGIN ")
+        .and_then(|v| v.split_once("-----"));
+    let (label, rest) = label.ok_or_else(|| anyhow!("not a PEM"))?;
+    let end = format!("-----END {label}-----");
+    let (body, _) = rest
+        .split_once(&end)
+        .ok_or_else(|| anyhow!("PEM is not closed"))?;
+    let der = base64_decode(body).ok_or_else(|| anyhow!("PEM has invalid base64"))?;
+    Ok((label, der))
+}
+
+/// Validate and store the value from editable text.
+pub fn set_text(table: Table, k: &str, text: &str) -> Result<()> {
+    let v = match (table, k) {
+        (Table::Admin, "ssl_cert") => {
+            let (label, der) = pem_decode(text)?;
+            if label != "CERTIFICATE" {
+                bail!("expect CERTIFICATE, but got {label}");
+            }
+            webpki::EndEntityCert::try_from(&der[..])
+                .map_err(|e| anyhow!("invalid certificate: {e:?}"))?;
+            der
+        }
+        (Table::Admin, "ssl_key") => {
+            let (label, der) = pem_decode(text)?;
+            if label != "PRIVATE KEY" {
+                bail!("expect PRIVATE KEY (PKCS#8), but got {label}");
+            }
+            let key = PrivateKey(der);
+            sign::any_supported_type(&key).map_err(|e| anyhow!("invalid private key: {e}"))?;
+            key.0
+        }
+        (Table::Admin, "trusted_proxies") => {
+            let items = text.split(|c: char| c == ',' || c.is_whitespace());
+            if let Some(v) = items
+                .filter(|v| !v.is_empty())
+                .find(|v| remote::parse_net(v).is_none())
+            {
+                bail!("invalid IP or CIDR: {v}");
+            }
+            text.trim().into()
+        }
+        (Table::Admin, TEMP_CERT_MARK) => bail!("managed by server, read only"),
+        (Table::QqbotCfg, k) => {
+            qqbot::check_cfg(k, text.as_bytes())?;
+            text.into()
+        }
+        _ => text.into(),
+    };
+    db_set(table, k, v);
+    if matches!((table, k), (Table::Admin, "ssl_cert")) {
+        db_delete(Table::Admin, TEMP_CERT_MARK);
+    }
+    Ok(())
+}
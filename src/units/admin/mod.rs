@@ -1,10 +1,28 @@
 //! Admin console.
+mod backup;
+mod kv;
+mod sql;
 
-use crate::{db, include_page};
-use axum::body::Bytes;
-use axum::extract::RawQuery;
-use axum::response::Html;
+use crate::client::proxy;
+use crate::log::{self, Filter};
+use crate::ticker::Ticker;
+use crate::tls::TEMP_CERT_MARK;
+use crate::utils::{CompressedPage, RecvStream};
+use crate::{access, care, db, include_page, limit, template, units};
+use axum::body::{HttpBody, StreamBody};
+use axum::extract::{Form, Path, Query, RawBody};
+use axum::http::header::{CONTENT_DISPOSITION, CONTENT_TYPE};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::sse::{Event, Sse};
+use axum::response::{Html, IntoResponse, Json, Response};
 use axum::routing::{MethodRouter, Router};
+use kv::Table;
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use sql::SqlForm;
+use std::fmt::Write;
+use std::time::UNIX_EPOCH;
+use tokio::io::AsyncWriteExt;
 
 fn db_init() {
     // db!("VACUUM");
@@ -14,14 +32,7 @@ fn db_init() {
     "}
     .unwrap();
 }
-fn db_set(k: &str, v: Vec<u8>) {
-    db! {"
-        REPLACE INTO admin
-        VALUES (?1, ?2)
-    ", [k, v]}
-    .unwrap();
-}
-fn _db_get(k: &str) -> Option<(Vec<u8>,)> {
+fn db_get(k: &str) -> Option<(Vec<u8>,)> {
     db! {"
         SELECT v FROM admin
         WHERE k = ?
@@ -29,19 +40,367 @@ fn _db_get(k: &str) -> Option<(Vec<u8>,)> {
     .ok()
 }
 
-async fn post_handler(q: RawQuery, body: Bytes) {
-    let q = q.0.unwrap();
-    let k = q.split_once('=').unwrap().1;
-    db_set(k, body.into());
+async fn get_handler() -> Html<String> {
+    let warning = db_get(TEMP_CERT_MARK)
+        .map(|_| "The ssl_cert is a temporary self-signed one, set yours and restart.");
+    let mut o = template!("page.html").writer();
+    o.section("warning", warning, |o, v| {
+        o.slot("text", v);
+    });
+    Html(o.finish())
+}
+
+#[derive(Deserialize)]
+struct KvQuery {
+    /// View as editable text, otherwise download the raw value.
+    text: Option<String>,
+}
+
+async fn kv_get_handler(
+    Path((table, k)): Path<(Table, String)>,
+    Query(q): Query<KvQuery>,
+) -> Response {
+    if q.text.is_some() {
+        return match kv::get_text(table, &k) {
+            Ok(Some(v)) => v.into_response(),
+            Ok(None) => StatusCode::NOT_FOUND.into_response(),
+            Err(e) => (StatusCode::UNPROCESSABLE_ENTITY, e.to_string()).into_response(),
+        };
+    }
+    let v = match kv::get(table, &k) {
+        Some(v) => v,
+        None => return StatusCode::NOT_FOUND.into_response(),
+    };
+    let filename = k.replace(
+        |c: char| !c.is_ascii_alphanumeric() && c != '_' && c != '.',
+        "_",
+    );
+    let headers = [
+        (CONTENT_TYPE, "application/octet-stream".to_string()),
+        (
+            CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{filename}\""),
+        ),
+    ];
+    (headers, v).into_response()
+}
+
+async fn kv_put_handler(
+    Path((table, k)): Path<(Table, String)>,
+    body: String,
+) -> Result<(), (StatusCode, String)> {
+    kv::set_text(table, &k, &body).map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))
+}
+
+async fn kv_delete_handler(Path((table, k)): Path<(Table, String)>) -> StatusCode {
+    match kv::delete(table, &k) {
+        true => StatusCode::OK,
+        false => StatusCode::NOT_FOUND,
+    }
+}
+
+/// The password of backup encryption, in header to keep it out of the access log.
+const BACKUP_PASSWORD: &str = "x-backup-password";
+
+fn backup_password(headers: &HeaderMap) -> Option<String> {
+    let v = headers.get(BACKUP_PASSWORD)?.to_str().ok()?;
+    Some(v.to_string()).filter(|v| !v.is_empty())
+}
+
+#[derive(Deserialize)]
+struct BackupQuery {
+    gzip: Option<String>,
+}
+
+async fn backup_get_handler(Query(q): Query<BackupQuery>, headers: HeaderMap) -> Response {
+    let password = backup_password(&headers);
+    let mut name = format!("ksite-{}.tar", UNIX_EPOCH.elapsed().unwrap().as_secs());
+    if q.gzip.is_some() {
+        name += ".gz";
+    }
+    if password.is_some() {
+        name += ".enc";
+    }
+    let headers = [
+        (CONTENT_TYPE, "application/octet-stream".to_string()),
+        (
+            CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{name}\""),
+        ),
+    ];
+    let stream = backup::export_stream(q.gzip.is_some(), password);
+    (headers, StreamBody::new(stream)).into_response()
+}
+
+async fn backup_post_handler(
+    headers: HeaderMap,
+    RawBody(mut body): RawBody,
+) -> Result<String, (StatusCode, String)> {
+    let internal = |e: std::io::Error| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string());
+    // save to file first, the upload may be large
+    let path = backup::temp_path("upload").map_err(internal)?;
+    let ret = async {
+        let mut file = tokio::fs::File::create(&path).await.map_err(internal)?;
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk.map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+            file.write_all(&chunk).await.map_err(internal)?;
+        }
+        file.flush().await.map_err(internal)?;
+        let (path, password) = (path.clone(), backup_password(&headers));
+        let ret = tokio::task::spawn_blocking(move || backup::import(&path, password.as_deref()));
+        ret.await.unwrap().map_err(|e| (StatusCode::BAD_REQUEST, e))
+    }
+    .await;
+    tokio::fs::remove_file(&path).await.ok();
+    ret
+}
+
+async fn sql_post_handler(Form(form): Form<SqlForm>) -> Response {
+    // may be blocked by the lock, until other queries timeout
+    let ret = tokio::task::spawn_blocking(move || sql::query(&form).map(|v| (form, v)));
+    let (form, rows) = match ret.await.unwrap() {
+        Ok(v) => v,
+        Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+    };
+    let (body, ext, mime) = match form.format() {
+        None => return Json(sql::to_json(&rows)).into_response(),
+        Some("csv") => (sql::to_csv(&rows), "csv", "text/csv"),
+        Some("json") => (sql::to_json(&rows).to_string(), "json", "application/json"),
+        Some(_) => return StatusCode::BAD_REQUEST.into_response(),
+    };
+    let headers = [
+        (CONTENT_TYPE, mime.to_string()),
+        (
+            CONTENT_DISPOSITION,
+            format!("attachment; filename=\"query.{ext}\""),
+        ),
+    ];
+    (headers, body).into_response()
+}
+
+#[derive(Deserialize)]
+struct AccessQuery {
+    #[serde(default)]
+    q: String,
+    limit: Option<u32>,
+    json: Option<String>,
+}
+
+async fn access_search_handler(Query(q): Query<AccessQuery>) -> Response {
+    let entries = access::search(&q.q, q.limit.unwrap_or(256));
+    if q.json.is_some() {
+        return Json(entries).into_response();
+    }
+    let mut body = String::new();
+    for entry in entries {
+        writeln!(body, "{entry}").unwrap();
+    }
+    body.into_response()
+}
+
+async fn access_tail_handler() -> impl IntoResponse {
+    Sse::new(RecvStream::new(access::tail(), |v| {
+        Event::default().data(v.to_string())
+    }))
+}
+
+#[derive(Deserialize)]
+struct LogQuery {
+    limit: Option<u32>,
+    json: Option<String>,
+}
+
+async fn log_search_handler(Query(filter): Query<Filter>, Query(q): Query<LogQuery>) -> Response {
+    let records = log::search(&filter, q.limit.unwrap_or(256));
+    if q.json.is_some() {
+        return Json(records).into_response();
+    }
+    let mut body = String::new();
+    for record in records {
+        writeln!(body, "{record}").unwrap();
+    }
+    body.into_response()
+}
+
+async fn log_tail_handler(Query(filter): Query<Filter>) -> impl IntoResponse {
+    Sse::new(RecvStream::filter_map(log::tail(), move |v| {
+        filter
+            .matches(&v)
+            .then(|| Event::default().data(v.to_string()))
+    }))
+}
+
+/// Set limits, one `key rate burst` per line.
+async fn limit_post_handler(body: String) -> Result<(), StatusCode> {
+    let mut cfgs = Vec::new();
+    for line in body
+        .lines()
+        .filter(|v| !v.starts_with('#') && !v.trim().is_empty())
+    {
+        match line.split_whitespace().collect::<Vec<_>>()[..] {
+            [k, rate, burst] => match (rate.parse(), burst.parse()) {
+                (Ok(rate), Ok(burst)) if limit::check_cfg(rate, burst) => {
+                    cfgs.push((k.to_string(), rate, burst))
+                }
+                _ => return Err(StatusCode::BAD_REQUEST),
+            },
+            _ => return Err(StatusCode::BAD_REQUEST),
+        }
+    }
+    for (k, rate, burst) in cfgs {
+        limit::set_cfg(&k, rate, burst);
+    }
+    Ok(())
+}
+
+/// Set proxy configs, one `key value` per line, empty value to delete.
+async fn proxy_post_handler(body: String) -> Result<(), (StatusCode, String)> {
+    for line in body
+        .lines()
+        .filter(|v| !v.starts_with('#') && !v.trim().is_empty())
+    {
+        let (k, v) = line.trim().split_once(' ').unwrap_or((line.trim(), ""));
+        if let Err(e) = proxy::set_cfg(k, v.trim()) {
+            return Err((StatusCode::BAD_REQUEST, format!("{k}: {e}")));
+        }
+    }
+    Ok(())
+}
+
+/// Enable or disable units, one `name on|off` per line.
+async fn units_post_handler(body: String) -> Result<(), (StatusCode, String)> {
+    let mut states = Vec::new();
+    for line in body
+        .lines()
+        .filter(|v| !v.starts_with('#') && !v.trim().is_empty())
+    {
+        match line.split_whitespace().collect::<Vec<_>>()[..] {
+            [name, "on"] => states.push((name, true)),
+            [name, "off"] => states.push((name, false)),
+            _ => return Err((StatusCode::BAD_REQUEST, format!("invalid line {line}"))),
+        }
+    }
+    for (name, enabled) in states {
+        if let Err(e) = units::set_enabled(name, enabled) {
+            return Err((StatusCode::BAD_REQUEST, format!("{name}: {e}")));
+        }
+    }
+    Ok(())
+}
+
+async fn settings_post_handler(
+    Path(name): Path<String>,
+    body: String,
+) -> Result<(), (StatusCode, String)> {
+    units::set_settings(&name, &body).map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))
 }
 
 pub fn service() -> Router {
     db_init();
-    Router::new().route(
-        "/admin",
-        MethodRouter::new()
-            .get(|| async { Html((include_page!("page.html") as [_; 1])[0]) })
-            .post(post_handler)
-            .layer(crate::auth::auth_layer()),
-    )
+    kv::init();
+    Router::new()
+        .route("/admin", MethodRouter::new().get(get_handler))
+        .route(
+            "/admin/kv",
+            MethodRouter::new().get(|| async { Json(kv::list()) }),
+        )
+        .route(
+            "/admin/kv/:table/:k",
+            MethodRouter::new()
+                .get(kv_get_handler)
+                .put(kv_put_handler)
+                .delete(kv_delete_handler),
+        )
+        .route(
+            "/admin/backup",
+            MethodRouter::new()
+                .get(backup_get_handler)
+                .post(backup_post_handler),
+        )
+        .route(
+            "/admin/sql",
+            MethodRouter::new()
+                .get(|headers: HeaderMap| async move {
+                    const RAW: &str = (include_page!("sql.html") as [_; 1])[0];
+                    static PAGE: CompressedPage = CompressedPage::from_static(RAW);
+                    PAGE.respond(&headers)
+                })
+                .post(sql_post_handler),
+        )
+        .route(
+            "/admin/access",
+            MethodRouter::new().get(|headers: HeaderMap| async move {
+                const RAW: &str = (include_page!("access.html") as [_; 1])[0];
+                static PAGE: CompressedPage = CompressedPage::from_static(RAW);
+                PAGE.respond(&headers)
+            }),
+        )
+        .route(
+            "/admin/access/search",
+            MethodRouter::new().get(access_search_handler),
+        )
+        .route(
+            "/admin/access/tail",
+            MethodRouter::new().get(access_tail_handler),
+        )
+        .route(
+            "/admin/log",
+            MethodRouter::new().get(|headers: HeaderMap| async move {
+                const RAW: &str = (include_page!("log.html") as [_; 1])[0];
+                static PAGE: CompressedPage = CompressedPage::from_static(RAW);
+                PAGE.respond(&headers)
+            }),
+        )
+        .route(
+            "/admin/log/search",
+            MethodRouter::new().get(log_search_handler),
+        )
+        .route("/admin/log/tail", MethodRouter::new().get(log_tail_handler))
+        .route(
+            "/admin/limit",
+            MethodRouter::new()
+                .get(|| async { limit::status() })
+                .post(limit_post_handler),
+        )
+        .route(
+            "/admin/units",
+            MethodRouter::new()
+                .get(|| async { units::status() })
+                .post(units_post_handler),
+        )
+        .route(
+            "/admin/settings/:name",
+            MethodRouter::new()
+                .get(|Path(name): Path<String>| async move { units::settings(&name) })
+                .post(settings_post_handler),
+        )
+        .route(
+            "/admin/proxy",
+            MethodRouter::new()
+                .get(|| async { proxy::status() })
+                .post(proxy_post_handler),
+        )
+        .layer(crate::auth::auth_layer())
+}
+
+static TICKER: Lazy<Ticker> = Lazy::new(|| Ticker::new_p8(&[(4, 10, 0)]));
+pub async fn tick() {
+    if !TICKER.tick() {
+        return;
+    }
+
+    let ret = tokio::task::spawn_blocking(backup::scheduled).await;
+    care!(ret.unwrap()).ok();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn page() {
+        db_init();
+        db!("REPLACE INTO admin VALUES (?1, x'')", [TEMP_CERT_MARK]).unwrap();
+        assert!(get_handler().await.0.contains("temporary self-signed"));
+    }
 }
@@ -0,0 +1,138 @@
+//! Read-only SQL console.
+//!
+//! Enforced by `PRAGMA query_only`, and only the statements below are accepted, because some
+//! pragmas and `ATTACH` are not writes but still change the connection. Only the first statement
+//! runs if there are multiple.
+use crate::database::{self, Rows};
+use rusqlite::types::Value;
+use serde::Deserialize;
+use std::fmt::Write;
+use std::time::Duration;
+
+const TIMEOUT: Duration = Duration::from_secs(3);
+const PAGE_SIZE: usize = 100;
+const EXPORT_LIMIT: usize = 100_000;
+const STATEMENTS: [&str; 5] = ["SELECT", "WITH", "VALUES", "EXPLAIN", "PRAGMA"];
+/// The pragmas which only read, and without `=` assignment.
+const PRAGMAS: [&str; 13] = [
+    "compile_options",
+    "database_list",
+    "foreign_key_list",
+    "freelist_count",
+    "index_info",
+    "index_list",
+    "index_xinfo",
+    "integrity_check",
+    "page_count",
+    "page_size",
+    "quick_check",
+    "table_info",
+    "table_list",
+];
+
+fn check(sql: &str) -> Result<(), String> {
+    let words = sql.split(|c: char| !c.is_ascii_alphanumeric() && c != '_');
+    let mut words = words.filter(|v| !v.is_empty());
+    let first = words.next().unwrap_or_default().to_ascii_uppercase();
+    if !STATEMENTS.contains(&first.as_str()) {
+        return Err(format!("only {} are allowed", STATEMENTS.join(", ")));
+    }
+    if first == "PRAGMA" {
+        let name = words.next().unwrap_or_default().to_ascii_lowercase();
+        if !PRAGMAS.contains(&name.as_str()) || sql.contains('=') {
+            return Err(format!("only pragmas {} are allowed", PRAGMAS.join(", ")));
+        }
+    }
+    Ok(())
+}
+
+#[derive(Deserialize)]
+pub struct SqlForm {
+    sql: String,
+    #[serde(default)]
+    offset: usize,
+    /// Export all rows as `csv` or `json`, otherwise returns a page.
+    format: Option<String>,
+}
+
+impl SqlForm {
+    pub fn format(&self) -> Option<&str> {
+        self.format.as_deref().filter(|v| !v.is_empty())
+    }
+}
+
+pub fn query(form: &SqlForm) -> Result<Rows, String> {
+    check(&form.sql)?;
+    let (offset, limit) = match form.format() {
+        Some(_) => (0, EXPORT_LIMIT),
+        None => (form.offset, PAGE_SIZE),
+    };
+    database::query_readonly(&form.sql, offset, limit, TIMEOUT).map_err(|e| e.to_string())
+}
+
+fn cell_json(v: &Value) -> serde_json::Value {
+    match v {
+        Value::Null => serde_json::Value::Null,
+        Value::Integer(v) => (*v).into(),
+        Value::Real(v) => (*v).into(),
+        Value::Text(v) => v.as_str().into(),
+        Value::Blob(_) => to_text(v).into(),
+    }
+}
+
+/// The text form, blob in SQL literal like `x'0a1b'`.
+fn to_text(v: &Value) -> String {
+    match v {
+        Value::Null => String::new(),
+        Value::Integer(v) => v.to_string(),
+        Value::Real(v) => v.to_string(),
+        Value::Text(v) => v.clone(),
+        Value::Blob(v) => {
+            let mut o = String::from("x'");
+            for b in v {
+                write!(o, "{b:02x}").unwrap();
+            }
+            o + "'"
+        }
+    }
+}
+
+/// Like `{ "columns": ["k"], "rows": [["foo"]], "more": false }`.
+pub fn to_json(rows: &Rows) -> serde_json::Value {
+    let lines = rows
+        .rows
+        .iter()
+        .map(|row| row.iter().map(cell_json).collect());
+    serde_json::json!({
+        "columns": rows.columns,
+        "rows": lines.collect::<Vec<Vec<_>>>(),
+        "more": rows.more,
+    })
+}
+
+/// RFC 4180 CSV, with header line.
+pub fn to_csv(rows: &Rows) -> String {
+    fn field(o: &mut String, v: &str) {
+        match v.contains([',', '"', '\r', '\n']) {
+            true => write!(o, "\"{}\"", v.replace('"', "\"\"")).unwrap(),
+            false => *o += v,
+        }
+    }
+    let mut o = String::new();
+    let lines = std::iter::once(rows.columns.clone());
+    let lines = lines.chain(
+        rows.rows
+            .iter()
+            .map(|row| row.iter().map(to_text).collect()),
+    );
+    for line in lines {
+        for (i, v) in line.iter().enumerate() {
+            if i != 0 {
+                o += ",";
+            }
+            field(&mut o, v);
+        }
+        o += "\r\n";
+    }
+    o
+}
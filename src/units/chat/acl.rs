@@ -0,0 +1,149 @@
+//! Access control of the created rooms.
+//!
+//! Rooms are open if not created by `create`, for compatibility. A created room is joined with the
+//! `key`, which is one of the join secret, an invite token or the owner token. Only the SHA-256
+//! of them are stored.
+use crate::db;
+use axum::http::StatusCode;
+use ring::constant_time::verify_slices_are_equal;
+use ring::digest::{digest, SHA256};
+use std::fmt::Write;
+
+fn db_init() {
+    db! {"
+        CREATE TABLE IF NOT EXISTS chat_rooms
+        (room INTEGER PRIMARY KEY, owner BLOB, secret BLOB, closed INTEGER)
+    "}
+    .unwrap();
+    db! {"
+        CREATE TABLE IF NOT EXISTS chat_invites
+        (token BLOB PRIMARY KEY, room INTEGER, expire INTEGER)
+    "}
+    .unwrap();
+}
+fn db_room_insert(room: u32, owner: Vec<u8>, secret: Option<Vec<u8>>) -> bool {
+    db! {"
+        INSERT INTO chat_rooms
+        VALUES (?1, ?2, ?3, 0)
+    ", [room, owner, secret]}
+    .is_ok()
+}
+fn db_room_get(room: u32) -> Option<(Vec<u8>, Option<Vec<u8>>, bool)> {
+    db! {"
+        SELECT owner, secret, closed FROM chat_rooms
+        WHERE room = ?
+    ", [room], ^(0, 1, 2)}
+    .ok()
+}
+fn db_room_close(room: u32) {
+    db!("UPDATE chat_rooms SET closed = 1 WHERE room = ?", [room]).unwrap();
+    db!("DELETE FROM chat_invites WHERE room = ?", [room]).unwrap();
+}
+fn db_room_set_secret(room: u32, secret: Option<Vec<u8>>) {
+    db! {"
+        UPDATE chat_rooms SET secret = ?2
+        WHERE room = ?1
+    ", [room, secret]}
+    .unwrap();
+    db!("DELETE FROM chat_invites WHERE room = ?", [room]).unwrap();
+}
+fn db_invite_insert(token: Vec<u8>, room: u32, expire: u64) {
+    db! {"
+        INSERT INTO chat_invites
+        VALUES (?1, ?2, ?3)
+    ", [token, room, expire]}
+    .unwrap();
+}
+fn db_invite_valid(token: Vec<u8>, room: u32) -> bool {
+    let (n,): (i64,) = db! {"
+        SELECT count(*) FROM chat_invites
+        WHERE token = ?1 AND room = ?2 AND expire > strftime('%s','now')
+    ", [token, room], ^(0)}
+    .unwrap();
+    n > 0
+}
+fn db_invite_clean() {
+    db! {"
+        DELETE FROM chat_invites
+        WHERE expire <= strftime('%s','now')
+    "}
+    .unwrap();
+}
+
+pub fn init() {
+    db_init();
+}
+
+pub fn clean() {
+    db_invite_clean();
+}
+
+/// Invite tokens are valid for 1 day.
+pub const INVITE_TTL: u64 = 3600 * 24;
+
+fn hash(key: &str) -> Vec<u8> {
+    digest(&SHA256, key.as_bytes()).as_ref().to_vec()
+}
+
+/// Random token in hex.
+pub fn token() -> String {
+    let mut o = String::new();
+    for b in rand::random::<[u8; 16]>() {
+        write!(o, "{b:02x}").unwrap();
+    }
+    o
+}
+
+#[derive(PartialEq, Eq)]
+pub enum Role {
+    Member,
+    Owner,
+}
+
+/// Check the key of the room, the closed rooms are `404`.
+pub fn authorize(room: u32, key: &str) -> Result<Role, (StatusCode, &'static str)> {
+    let (owner, secret, closed) = match db_room_get(room) {
+        Some(v) => v,
+        None => return Ok(Role::Member),
+    };
+    if closed {
+        return Err((StatusCode::NOT_FOUND, "room closed"));
+    }
+    let h = hash(key);
+    if verify_slices_are_equal(&h, &owner).is_ok() {
+        Ok(Role::Owner)
+    } else if secret.is_some_and(|v| verify_slices_are_equal(&h, &v).is_ok())
+        || db_invite_valid(h, room)
+    {
+        Ok(Role::Member)
+    } else {
+        Err((StatusCode::FORBIDDEN, "invalid key"))
+    }
+}
+
+/// Returns the owner token, the room is invite-only if the secret is empty.
+pub fn create(room: u32, secret: &str) -> Option<String> {
+    let owner = token();
+    let secret = Some(secret).filter(|v| !v.is_empty()).map(hash);
+    db_room_insert(room, hash(&owner), secret).then_some(owner)
+}
+
+/// Replace the secret and revoke the invites, the joined connections are not affected.
+pub fn set_secret(room: u32, secret: &str) {
+    let secret = Some(secret).filter(|v| !v.is_empty()).map(hash);
+    db_room_set_secret(room, secret);
+}
+
+pub fn invite(room: u32, expire: u64) -> String {
+    let v = token();
+    db_invite_insert(hash(&v), room, expire);
+    v
+}
+
+pub fn close(room: u32) {
+    db_room_close(room);
+}
+
+pub fn is_closed(room: u32) -> bool {
+    matches!(db_room_get(room), Some((.., true)))
+}
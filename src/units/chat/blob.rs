@@ -0,0 +1,93 @@
+//! Temporary store of attachments, they are encrypted by clients before uploading.
+//!
+//! A blob is referenced by its id in the messages, and removed after `TTL` or when the room is
+//! closed. The total size is limited by `ROOM_QUOTA` per room, and by `QUOTA` for all.
+use super::{acl, Error};
+use crate::db;
+use axum::http::StatusCode;
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+
+fn db_init() {
+    db! {"
+        CREATE TABLE IF NOT EXISTS chat_blobs
+        (id TEXT PRIMARY KEY, room INTEGER, expire INTEGER, data BLOB)
+    "}
+    .unwrap();
+}
+fn db_insert(id: &str, room: u32, expire: u64, data: &[u8]) {
+    db! {"
+        INSERT INTO chat_blobs
+        VALUES (?1, ?2, ?3, ?4)
+    ", [id, room, expire, data]}
+    .unwrap();
+}
+fn db_get(room: u32, id: &str) -> Option<(Vec<u8>,)> {
+    db! {"
+        SELECT data FROM chat_blobs
+        WHERE room = ?1 AND id = ?2 AND expire > strftime('%s','now')
+    ", [room, id], ^(0)}
+    .ok()
+}
+fn db_size(room: u32) -> (u64, u64) {
+    db! {"
+        SELECT coalesce(sum(length(data)) FILTER (WHERE room = ?), 0),
+            coalesce(sum(length(data)), 0)
+        FROM chat_blobs
+    ", [room], ^(0, 1)}
+    .unwrap()
+}
+fn db_delete(room: u32) {
+    db!("DELETE FROM chat_blobs WHERE room = ?", [room]).unwrap();
+}
+fn db_clean() {
+    db! {"
+        DELETE FROM chat_blobs
+        WHERE expire <= strftime('%s','now')
+    "}
+    .unwrap();
+}
+
+pub fn init() {
+    db_init();
+}
+
+pub fn clean() {
+    db_clean();
+}
+
+/// Remove the blobs of the room.
+pub fn delete(room: u32) {
+    db_delete(room);
+}
+
+/// Larger uploads are rejected by the body limit.
+pub const BLOB_MAX: usize = 8 << 20;
+/// The total size of the blobs in a room, including the expired but not cleaned.
+const ROOM_QUOTA: u64 = 64 << 20;
+/// The total size of all rooms.
+const QUOTA: u64 = 1 << 30;
+/// Blobs are kept for 1 day.
+const TTL: u64 = 3600 * 24;
+
+/// Store a blob, returns the id and the expire time.
+pub fn put(room: u32, data: &[u8]) -> Result<(String, u64), Error> {
+    // hold the lock, or concurrent uploads may pass the check together
+    static PUT: Mutex<()> = Mutex::new(());
+    let _guard = PUT.lock().unwrap();
+    let (room_size, size) = db_size(room);
+    if room_size + data.len() as u64 > ROOM_QUOTA {
+        return Err((StatusCode::INSUFFICIENT_STORAGE, "room quota exceeded"));
+    }
+    if size + data.len() as u64 > QUOTA {
+        return Err((StatusCode::INSUFFICIENT_STORAGE, "quota exceeded"));
+    }
+    let id = acl::token();
+    let expire = UNIX_EPOCH.elapsed().unwrap().as_secs() + TTL;
+    db_insert(&id, room, expire, data);
+    Ok((id, expire))
+}
+
+pub fn get(room: u32, id: &str) -> Option<Vec<u8>> {
+    db_get(room, id).map(|v| v.0)
+}
@@ -0,0 +1,209 @@
+//! Bridges between rooms and QQ groups, configured by the settings of `chat`.
+//!
+//! Only the plaintext messages are mirrored, they are JSON like
+//! `{ "op": "text", "id": "sender", "value": "..." }`. The messages from groups are marked by
+//! `"via": "qq"` and never sent back, and `qqbot` ignores the messages sent by itself.
+use super::{acl, publish, Feed, Frame};
+use crate::{log, units};
+use anyhow::Result;
+use serde::Deserialize;
+use serde_json::json;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use tokio::task::JoinHandle;
+
+/// The QQ side, implemented by `qqbot`, or by mocks.
+pub trait Remote: Send + Sync {
+    fn send(&self, group: i64, text: String) -> Pin<Box<dyn Future<Output = Result<()>> + Send>>;
+}
+
+static REMOTE: Mutex<Option<Arc<dyn Remote>>> = Mutex::new(None);
+
+pub fn set_remote(remote: Arc<dyn Remote>) {
+    *REMOTE.lock().unwrap() = Some(remote);
+}
+
+/// The bridges, one `room group` per line.
+pub const DEFAULT_SETTINGS: &str = "";
+
+fn parse(v: &str) -> Result<Vec<(u32, i64)>> {
+    let mut ret = Vec::new();
+    for line in v.lines().map(str::trim).filter(|v| !v.is_empty()) {
+        let (room, group) = line.split_once(' ').unwrap_or((line, ""));
+        ret.push((room.parse()?, group.trim().parse()?));
+    }
+    Ok(ret)
+}
+
+pub fn check_settings(v: &str) -> Result<()> {
+    parse(v).map(|_| ())
+}
+
+#[derive(Deserialize)]
+struct Text {
+    op: String,
+    id: String,
+    value: String,
+    via: Option<String>,
+}
+
+/// Forward the plaintext messages of the room to the group, joins as `qq:group`.
+async fn forward(room: u32, group: i64) {
+    let mut feed = Feed::new(room, &format!("qq:{group}"), None);
+    while let Some(frame) = feed.next().await {
+        let text = match frame {
+            Frame::Msg(_, msg) => serde_json::from_str::<Text>(&msg).ok(),
+            _ => None,
+        };
+        let text = match text.filter(|v| v.op == "text" && v.via.is_none()) {
+            Some(v) => v,
+            None => continue, // encrypted, or from groups
+        };
+        let remote = match REMOTE.lock().unwrap().clone() {
+            Some(v) => v,
+            None => continue, // not registered by `qqbot` yet
+        };
+        if let Err(e) = remote
+            .send(group, format!("[{}] {}", text.id, text.value))
+            .await
+        {
+            log!(Warn, "bridge {room} to {group} failed: {e}");
+        }
+    }
+    log!(Warn, "bridge {room} to {group} ended, kicked or closed");
+}
+
+struct Bridges {
+    /// The settings they were started by.
+    settings: String,
+    pairs: Vec<(u32, i64)>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+static BRIDGES: Mutex<Bridges> = Mutex::new(Bridges {
+    settings: String::new(),
+    pairs: Vec::new(),
+    tasks: Vec::new(),
+});
+
+/// Restart the bridges if the settings changed, or stop them if `chat` is disabled.
+pub fn sync() {
+    let settings = match units::enabled("chat") {
+        true => units::settings("chat"),
+        false => String::new(),
+    };
+    let mut bridges = BRIDGES.lock().unwrap();
+    if bridges.settings == settings {
+        return;
+    }
+    for task in bridges.tasks.drain(..) {
+        task.abort(); // leaves the room on drop
+    }
+    let pairs = parse(&settings).unwrap_or_default();
+    let tasks = pairs
+        .iter()
+        .map(|&(room, group)| tokio::spawn(forward(room, group)));
+    let tasks = tasks.collect();
+    *bridges = Bridges {
+        settings,
+        pairs,
+        tasks,
+    };
+}
+
+/// Publish a message of the group into the bridged rooms, except the closed ones.
+pub fn incoming(group: i64, sender: &str, text: &str) {
+    if !units::enabled("chat") {
+        return; // the bridges are stopped by the next `sync`
+    }
+    let pairs = BRIDGES.lock().unwrap().pairs.clone();
+    let msg = json!({ "op": "text", "id": sender, "value": text, "via": "qq" });
+    for (room, _) in pairs.into_iter().filter(|v| v.1 == group) {
+        if acl::is_closed(room) {
+            continue;
+        }
+        if let Err(e) = publish(room, msg.to_string()) {
+            log!(Warn, "bridge {group} to {room} failed: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{db_delete, db_init, db_since, ROOMS};
+    use super::*;
+    use crate::db;
+    use std::time::Duration;
+    use tokio::sync::mpsc::{self, UnboundedSender};
+    use tokio::time::{sleep, timeout};
+
+    struct Mock(UnboundedSender<(i64, String)>);
+
+    impl Remote for Mock {
+        fn send(
+            &self,
+            group: i64,
+            text: String,
+        ) -> Pin<Box<dyn Future<Output = Result<()>> + Send>> {
+            self.0.send((group, text)).unwrap();
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    #[tokio::test]
+    async fn forward_and_incoming() {
+        const ROOM: u32 = 90001;
+        const GROUP: i64 = 42;
+        db_init();
+        db_delete(ROOM);
+        db!("DELETE FROM chat_rooms WHERE room = ?", [ROOM]).ok();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        set_remote(Arc::new(Mock(tx)));
+        units::set_settings("chat", &format!("{ROOM} {GROUP}")).unwrap();
+        sync();
+        while !ROOMS.lock().unwrap().contains_key(&ROOM) {
+            sleep(Duration::from_millis(10)).await; // the forwarding task joins
+        }
+
+        // from the group, published into the room only
+        incoming(GROUP, "qq:1", "from group");
+        incoming(GROUP + 1, "qq:1", "not bridged");
+        let history = db_since(ROOM, 0);
+        assert_eq!(history.len(), 1);
+        let msg: serde_json::Value = serde_json::from_str(&history[0].1).unwrap();
+        assert_eq!(msg["via"], "qq");
+        let msg = json!({ "op": "text", "id": "a", "value": "b", "via": "qq" });
+        publish(ROOM, msg.to_string()).unwrap();
+        publish(ROOM, json!({ "op": "cipher", "data": "" }).to_string()).unwrap();
+
+        let msg = json!({ "op": "text", "id": "alice", "value": "hello" });
+        publish(ROOM, msg.to_string()).unwrap();
+        let received = timeout(Duration::from_secs(5), rx.recv()).await.unwrap();
+        assert_eq!(received, Some((GROUP, "[alice] hello".to_string())));
+        sleep(Duration::from_millis(100)).await;
+        assert!(rx.try_recv().is_err());
+
+        // not into the closed rooms
+        acl::init();
+        acl::create(ROOM, "").unwrap();
+        acl::close(ROOM);
+        incoming(GROUP, "qq:1", "closed");
+        assert_eq!(db_since(ROOM, 0).len(), 4);
+        db!("DELETE FROM chat_rooms WHERE room = ?", [ROOM]).unwrap();
+
+        // stopped if disabled
+        units::set_enabled("chat", false).unwrap();
+        sync();
+        while ROOMS.lock().unwrap().contains_key(&ROOM) {
+            sleep(Duration::from_millis(10)).await; // the forwarding task is aborted
+        }
+        incoming(GROUP, "qq:1", "disabled");
+        assert_eq!(db_since(ROOM, 0).len(), 4);
+        units::set_enabled("chat", true).unwrap();
+
+        units::set_settings("chat", "").unwrap();
+        sync();
+        db_delete(ROOM);
+    }
+}
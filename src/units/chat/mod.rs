@@ -1,42 +1,304 @@
 //! Simple chat rooms, client-to-client encrypted.
+//!
+//! The latest 256 messages of each room are kept for 7 days, it's safe since they are already
+//! encrypted. Reconnections replay the missed messages by the SSE event ids.
+//!
+//! Clients of SSE and WebSocket share the rooms. The created rooms require a key, see `acl`.
+//!
+//! Attachments are encrypted and uploaded into `blob` by clients, then the messages carry the
+//! blob ids and the decryption keys.
+//!
+//! Plaintext messages can be mirrored to QQ groups, see `bridge`.
+mod acl;
+mod blob;
+mod bridge;
+mod ws;
+pub use bridge::{check_settings, incoming, set_remote, Remote, DEFAULT_SETTINGS};
 
+use crate::db;
+use crate::limit::Throttle;
+use crate::ticker::Ticker;
+use crate::utils::CompressedPage;
 use anyhow::Result;
-use axum::extract::Path;
-use axum::http::header::CACHE_CONTROL;
+use axum::body::Bytes;
+use axum::extract::{DefaultBodyLimit, Path, Query};
+use axum::http::header::{HeaderMap, CACHE_CONTROL, CONTENT_TYPE};
+use axum::http::StatusCode;
 use axum::response::sse::{Event, Sse};
-use axum::response::{Html, IntoResponse};
+use axum::response::{IntoResponse, Json};
 use axum::routing::{MethodRouter, Router};
 use futures_core::{ready, Stream};
-use once_cell::sync::Lazy;
-use std::collections::HashMap;
+use once_cell::sync::{Lazy, OnceCell};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::future::Future;
 use std::pin::Pin;
-use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 use std::task::{Context, Poll};
+use std::time::UNIX_EPOCH;
+use tokio::sync::broadcast::error::RecvError;
 use tokio::sync::broadcast::{self, Receiver, Sender};
 
+fn db_init() {
+    // AUTOINCREMENT makes the ids never reused, they are the SSE event ids
+    db! {"
+        CREATE TABLE IF NOT EXISTS chat_history
+        (id INTEGER PRIMARY KEY AUTOINCREMENT, room INTEGER, time INTEGER, msg TEXT)
+    "}
+    .unwrap();
+    db! {"
+        CREATE INDEX IF NOT EXISTS chat_history_room
+        ON chat_history (room, id)
+    "}
+    .unwrap();
+}
+fn db_insert(room: u32, msg: &str) -> u64 {
+    let id = db! {"
+        INSERT INTO chat_history (room, time, msg)
+        VALUES (?1, strftime('%s','now'), ?2)
+    ", [room, msg], &}
+    .unwrap();
+    // keep the latest 256 messages of the room
+    db! {"
+        DELETE FROM chat_history
+        WHERE room = ?1 AND id <= (
+            SELECT id FROM chat_history WHERE room = ?1
+            ORDER BY id DESC LIMIT 1 OFFSET 256
+        )
+    ", [room]}
+    .unwrap();
+    id as _
+}
+fn db_last(room: u32) -> Option<u64> {
+    db! {"
+        SELECT max(id) FROM chat_history
+        WHERE room = ?
+    ", [room], ^(0)}
+    .unwrap()
+    .0
+}
+fn db_since(room: u32, id: u64) -> Vec<(u64, String)> {
+    db! {"
+        SELECT id, msg FROM chat_history
+        WHERE room = ?1 AND id > ?2
+        ORDER BY id
+    ", [room, id], (0, 1)}
+    .unwrap()
+}
+fn db_delete(room: u32) {
+    db!("DELETE FROM chat_history WHERE room = ?", [room]).unwrap();
+}
+fn db_clean() {
+    db! {"
+        DELETE FROM chat_history
+        WHERE strftime('%s','now') - time > 3600 * 24 * 7
+    "}
+    .unwrap();
+}
+
+/// Messages larger than this are rejected, as they are stored.
+const MSG_MAX: usize = 16 * 1024;
+/// In chars.
+const NAME_MAX: usize = 64;
+
+#[derive(Clone)]
+enum Frame {
+    /// A message in history, with the id.
+    Msg(u64, String),
+    /// An ephemeral JSON signal from the connection, like typing indicators, not stored.
+    Signal(u64, String),
+    /// Wake up the connections to check if kicked or closed, see `Member::ended`.
+    End,
+}
+
+type Error = (StatusCode, &'static str);
+
+/// Set once to `kicked` or `closed`.
+type Ended = Arc<OnceCell<&'static str>>;
+
 struct Room {
-    user_count: u32,
-    tx: Sender<String>,
+    /// The connections, with names, post limits and the ending flags.
+    members: BTreeMap<u64, (String, Throttle, Ended)>,
+    tx: Sender<Frame>,
+}
+
+impl Room {
+    fn members_json(&self) -> serde_json::Value {
+        let members = self.members.iter();
+        let members = members.map(|(conn, (name, ..))| json!({ "conn": conn, "name": name }));
+        json!({ "type": "members", "members": members.collect::<Vec<_>>() })
+    }
 }
 
-type SseStreamFut = Pin<Box<dyn Future<Output = (Option<String>, Receiver<String>)> + Send>>;
+/// The connected rooms, the history is kept in database after all users left.
+static ROOMS: Lazy<Mutex<HashMap<u32, Room>>> = Lazy::new(Default::default);
 
-struct SseStream {
-    id: u32,
-    fut: SseStreamFut,
+/// A connection in the room, leaves on drop.
+struct Member {
+    room: u32,
+    conn: u64,
+    /// Not by the `End` frame only, which is dropped if the receiver lagged.
+    ended: Ended,
 }
 
-impl SseStream {
-    fn new(id: u32, rx: Receiver<String>) -> Self {
-        SseStream {
-            id,
-            fut: Self::make_fut(rx),
+impl Member {
+    /// Join the room, returns the receiver, the id of the latest message and the member list.
+    /// The `key` should be checked before.
+    ///
+    /// The name is an ephemeral nickname or public key fingerprint, `conn` is used if empty.
+    fn join(room: u32, name: &str) -> (Member, Receiver<Frame>, u64, String) {
+        static CONN: AtomicU64 = AtomicU64::new(1);
+        let conn = CONN.fetch_add(1, Ordering::Relaxed);
+        let name: String = name.trim().chars().take(NAME_MAX).collect();
+        let name = if name.is_empty() {
+            conn.to_string()
+        } else {
+            name
+        };
+        let mut rooms = ROOMS.lock().unwrap();
+        let entry = rooms.entry(room).or_insert_with(|| Room {
+            members: BTreeMap::new(),
+            tx: broadcast::channel(16).0,
+        });
+        let v = json!({ "type": "join", "conn": conn, "name": name });
+        entry.tx.send(Frame::Signal(conn, v.to_string())).ok();
+        let ended = Ended::default();
+        let throttle = Throttle::new("chat_post");
+        entry.members.insert(conn, (name, throttle, ended.clone()));
+        // under the lock, no message is posted between them
+        let rx = entry.tx.subscribe();
+        let latest = db_last(room).unwrap_or(0);
+        let members = entry.members_json().to_string();
+        (Member { room, conn, ended }, rx, latest, members)
+    }
+}
+
+impl Drop for Member {
+    fn drop(&mut self) {
+        let mut rooms = ROOMS.lock().unwrap();
+        let room = rooms.get_mut(&self.room).unwrap();
+        let name = room.members.remove(&self.conn).map(|v| v.0);
+        if room.members.is_empty() {
+            rooms.remove(&self.room);
+            return;
+        }
+        let v = json!({ "type": "leave", "conn": self.conn, "name": name });
+        room.tx.send(Frame::Signal(self.conn, v.to_string())).ok();
+    }
+}
+
+/// Store and broadcast a message, returns the id.
+fn publish(room: u32, msg: String) -> Result<u64, &'static str> {
+    if msg.len() > MSG_MAX {
+        return Err("message too large");
+    }
+    // hold the lock, to keep the order of ids and broadcasting
+    let rooms = ROOMS.lock().unwrap();
+    let id = db_insert(room, &msg);
+    if let Some(entry) = rooms.get(&room) {
+        entry.tx.send(Frame::Msg(id, msg)).ok(); // no receivers is fine, it's in history
+    }
+    Ok(id)
+}
+
+/// Take a token of the connection's post limit.
+fn throttle(room: u32, conn: u64) -> Result<(), Error> {
+    let mut rooms = ROOMS.lock().unwrap();
+    let member = rooms.get_mut(&room).and_then(|v| v.members.get_mut(&conn));
+    let (_, throttle, _) = member.ok_or((StatusCode::BAD_REQUEST, "not joined"))?;
+    let e = (StatusCode::TOO_MANY_REQUESTS, "too many posts");
+    throttle.take().map_err(|_| e)
+}
+
+/// Broadcast an ephemeral signal, the sender will not receive it.
+fn signal(room: u32, conn: u64, v: String) {
+    if let Some(entry) = ROOMS.lock().unwrap().get(&room) {
+        entry.tx.send(Frame::Signal(conn, v)).ok();
+    }
+}
+
+/// The frames of a room for a connection, messages are continuous by replaying history.
+struct Feed {
+    member: Member,
+    /// The id of the last received message.
+    last: u64,
+    /// The `hello` and member list when joined, not sent yet.
+    intro: VecDeque<String>,
+    /// The ending signal is sent.
+    ended: bool,
+    /// The messages to replay, before receiving new ones.
+    replay: VecDeque<(u64, String)>,
+    rx: Receiver<Frame>,
+}
+
+impl Feed {
+    /// Replay the messages after `last`, or receive new messages only if `None`.
+    ///
+    /// The first frames are `hello` and the member list.
+    fn new(room: u32, name: &str, last: Option<u64>) -> Self {
+        let (member, rx, latest, members) = Member::join(room, name);
+        let last = last.unwrap_or(latest);
+        let hello = json!({ "type": "hello", "conn": member.conn, "last": last });
+        let replay = match last < latest {
+            true => db_since(room, last).into(),
+            false => VecDeque::new(),
+        };
+        Feed {
+            member,
+            last,
+            intro: [hello.to_string(), members].into(),
+            ended: false,
+            replay,
+            rx,
+        }
+    }
+
+    /// Cancel safe, can be used in `select!`.
+    async fn next(&mut self) -> Option<Frame> {
+        if let Some(v) = self.intro.pop_front() {
+            return Some(Frame::Signal(0, v));
+        }
+        if self.ended {
+            return None;
+        }
+        loop {
+            if let Some(kind) = self.member.ended.get() {
+                self.ended = true;
+                return Some(Frame::Signal(0, json!({ "type": kind }).to_string()));
+            }
+            if let Some((id, msg)) = self.replay.pop_front() {
+                self.last = id;
+                return Some(Frame::Msg(id, msg));
+            }
+            match self.rx.recv().await {
+                Ok(Frame::Msg(id, _)) if id <= self.last => continue, // already replayed
+                Ok(Frame::Msg(id, msg)) => self.replay.push_back((id, msg)),
+                Ok(Frame::Signal(conn, _)) if conn == self.member.conn => continue,
+                Ok(Frame::End) => continue, // checked above
+                Ok(v) => return Some(v),
+                // too slow to receive, replay the missed from history
+                Err(RecvError::Lagged(_)) => {
+                    self.replay = db_since(self.member.room, self.last).into()
+                }
+                Err(RecvError::Closed) => return None,
+            }
         }
     }
+}
+
+type SseStreamFut = Pin<Box<dyn Future<Output = (Option<Frame>, Feed)> + Send>>;
+
+struct SseStream(SseStreamFut);
+
+impl SseStream {
+    fn new(feed: Feed) -> Self {
+        SseStream(Self::make_fut(feed))
+    }
 
-    fn make_fut(mut rx: Receiver<String>) -> SseStreamFut {
-        Box::pin(async { (rx.recv().await.ok(), rx) })
+    fn make_fut(mut feed: Feed) -> SseStreamFut {
+        Box::pin(async { (feed.next().await, feed) })
     }
 }
 
@@ -45,60 +307,275 @@ impl Stream for SseStream {
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
         let this = self.get_mut();
-        let (value, rx) = ready!(Pin::new(&mut this.fut).poll(cx));
-        this.fut = Self::make_fut(rx);
-        Poll::Ready(value.map(|v| Ok(Event::default().data(v))))
+        let (value, feed) = ready!(Pin::new(&mut this.0).poll(cx));
+        this.0 = Self::make_fut(feed);
+        Poll::Ready(value.map(|v| match v {
+            Frame::Msg(id, msg) => Ok(Event::default().id(id.to_string()).data(msg)),
+            Frame::Signal(_, v) => Ok(Event::default().event("signal").data(v)),
+            Frame::End => unreachable!("converted by feed"),
+        }))
     }
 }
 
-impl Drop for SseStream {
-    fn drop(&mut self) {
-        let mut rooms = ROOMS.lock().unwrap();
-        let room = rooms.get_mut(&self.id).unwrap();
-        room.user_count -= 1;
-        if room.user_count == 0 {
-            rooms.remove(&self.id);
-            // println!("> rooms.remove({})", self.id);
+#[derive(Deserialize)]
+struct PostQuery {
+    #[serde(default)]
+    key: String,
+    /// The connection of SSE, to apply the post limit.
+    conn: u64,
+}
+
+async fn post_handler(
+    Path(id): Path<u32>,
+    Query(q): Query<PostQuery>,
+    msg: String,
+) -> Result<(), Error> {
+    acl::authorize(id, &q.key)?;
+    throttle(id, q.conn)?;
+    publish(id, msg).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    Ok(()) // empty response body means succeeded
+}
+
+/// Upload an attachment, returns the blob id. It takes a token of the post limit.
+async fn upload_handler(
+    Path(id): Path<u32>,
+    Query(q): Query<PostQuery>,
+    data: Bytes,
+) -> Result<Json<serde_json::Value>, Error> {
+    acl::authorize(id, &q.key)?;
+    throttle(id, q.conn)?;
+    let (blob, expire) = blob::put(id, &data)?;
+    Ok(Json(json!({ "blob": blob, "expire": expire })))
+}
+
+async fn download_handler(
+    Path((id, blob)): Path<(u32, String)>,
+    Query(q): Query<KeyQuery>,
+) -> Result<impl IntoResponse, Error> {
+    acl::authorize(id, &q.key)?;
+    let data = blob::get(id, &blob).ok_or((StatusCode::NOT_FOUND, "blob not found"))?;
+    let headers = [
+        (CONTENT_TYPE, "application/octet-stream"),
+        (CACHE_CONTROL, "private, max-age=86400"), // never changed
+    ];
+    Ok((headers, data))
+}
+
+#[derive(Deserialize)]
+struct SseQuery {
+    #[serde(default)]
+    key: String,
+    #[serde(default)]
+    name: String,
+}
+
+/// New connections receive new messages only, reconnections with `Last-Event-ID` header replay
+/// the missed. Signals, including `hello`, the member list and join or leave, are sent as
+/// `signal` events.
+async fn sse_handler(
+    Path(id): Path<u32>,
+    Query(q): Query<SseQuery>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, Error> {
+    acl::authorize(id, &q.key)?;
+    let last = headers
+        .get("last-event-id")
+        .and_then(|v| v.to_str().ok()?.parse().ok());
+    Ok(Sse::new(SseStream::new(Feed::new(id, &q.name, last))))
+}
+
+#[derive(Deserialize)]
+struct KeyQuery {
+    #[serde(default)]
+    key: String,
+}
+
+async fn count_handler(
+    Path(id): Path<u32>,
+    Query(q): Query<KeyQuery>,
+) -> Result<Json<serde_json::Value>, Error> {
+    acl::authorize(id, &q.key)?;
+    let rooms = ROOMS.lock().unwrap();
+    let count = rooms.get(&id).map_or(0, |v| v.members.len());
+    Ok(Json(json!({ "count": count })))
+}
+
+/// Create a room with a random id, the request body is the join secret.
+async fn create_handler(secret: String) -> Result<Json<serde_json::Value>, Error> {
+    // the ids rarely collide, so the failures are likely from database
+    for _ in 0..8 {
+        let id = rand::random::<u32>();
+        if ROOMS.lock().unwrap().contains_key(&id) || db_last(id).is_some() {
+            continue; // used as an open room
+        }
+        if let Some(owner) = acl::create(id, secret.trim()) {
+            return Ok(Json(json!({ "room": id, "owner": owner })));
         }
     }
+    Err((StatusCode::INTERNAL_SERVER_ERROR, "failed to create"))
 }
 
-static ROOMS: Lazy<Mutex<HashMap<u32, Room>>> = Lazy::new(Default::default);
+fn check_owner(id: u32, key: &str) -> Result<(), Error> {
+    match acl::authorize(id, key)? {
+        acl::Role::Owner => Ok(()),
+        acl::Role::Member => Err((StatusCode::FORBIDDEN, "owner only")),
+    }
+}
+
+async fn invite_handler(
+    Path(id): Path<u32>,
+    Query(q): Query<KeyQuery>,
+) -> Result<Json<serde_json::Value>, Error> {
+    check_owner(id, &q.key)?;
+    let expire = UNIX_EPOCH.elapsed().unwrap().as_secs() + acl::INVITE_TTL;
+    let invite = acl::invite(id, expire);
+    Ok(Json(json!({ "invite": invite, "expire": expire })))
+}
 
-async fn post_handler(Path(id): Path<u32>, msg: String) -> impl IntoResponse {
+/// Change the join secret and revoke the invites, the request body is the new secret.
+async fn secret_handler(
+    Path(id): Path<u32>,
+    Query(q): Query<KeyQuery>,
+    secret: String,
+) -> Result<(), Error> {
+    check_owner(id, &q.key)?;
+    acl::set_secret(id, secret.trim());
+    Ok(())
+}
+
+/// Disconnect a connection. It's able to join again with the key, change the secret before to
+/// prevent.
+async fn kick_handler(
+    Path((id, conn)): Path<(u32, u64)>,
+    Query(q): Query<KeyQuery>,
+) -> Result<(), Error> {
+    check_owner(id, &q.key)?;
     let rooms = ROOMS.lock().unwrap();
-    let room = match rooms.get(&id) {
-        Some(v) => v,
-        None => return "room not exist",
-    };
-    match room.tx.send(msg) {
-        Err(_) => "no receivers exist",
-        Ok(_) => "", // empty response body means succeeded
-    }
+    let room = rooms.get(&id).filter(|v| v.members.contains_key(&conn));
+    let room = room.ok_or((StatusCode::NOT_FOUND, "connection not found"))?;
+    room.members[&conn].2.set("kicked").ok();
+    room.tx.send(Frame::End).ok();
+    Ok(())
 }
 
-async fn sse_handler(Path(id): Path<u32>) -> impl IntoResponse {
-    let mut rooms = ROOMS.lock().unwrap();
-    let room = rooms.entry(id).or_insert_with(|| Room {
-        user_count: 0,
-        tx: broadcast::channel(16).0,
-    });
-    room.user_count += 1;
-    let rx = room.tx.subscribe();
-    Sse::new(SseStream::new(id, rx))
+/// Disconnect all and delete the history and blobs, the room can not be joined anymore.
+async fn close_handler(Path(id): Path<u32>, Query(q): Query<KeyQuery>) -> Result<(), Error> {
+    check_owner(id, &q.key)?;
+    let rooms = ROOMS.lock().unwrap();
+    acl::close(id);
+    db_delete(id);
+    blob::delete(id);
+    if let Some(room) = rooms.get(&id) {
+        for (.., ended) in room.members.values() {
+            ended.set("closed").ok(); // the kicked ones are kept
+        }
+        room.tx.send(Frame::End).ok();
+    }
+    Ok(())
 }
 
 pub fn service() -> Router {
+    db_init();
+    acl::init();
+    blob::init();
     Router::new()
         .route(
             "/chat", // https://127.0.0.1:9304/chat#123
-            MethodRouter::new().get(|| async {
-                (
-                    [(CACHE_CONTROL, "max-age=300")],
-                    Html(include_str!("page.html")),
-                )
+            MethodRouter::new().get(|headers: HeaderMap| async move {
+                static PAGE: CompressedPage =
+                    CompressedPage::from_static(include_str!("page.html"));
+                ([(CACHE_CONTROL, "max-age=300")], PAGE.respond(&headers))
             }),
         )
-        .route("/chat/post/:room", MethodRouter::new().post(post_handler))
+        .route(
+            "/chat/post/:room",
+            MethodRouter::new()
+                .post(post_handler)
+                .layer(DefaultBodyLimit::max(MSG_MAX)),
+        )
+        .route(
+            "/chat/blob/:room",
+            MethodRouter::new()
+                .post(upload_handler)
+                .layer(DefaultBodyLimit::max(blob::BLOB_MAX)),
+        )
+        .route(
+            "/chat/blob/:room/:blob",
+            MethodRouter::new().get(download_handler),
+        )
         .route("/chat/sse/:room", MethodRouter::new().get(sse_handler))
+        .route("/chat/ws/:room", MethodRouter::new().get(ws::handler))
+        .route("/chat/count/:room", MethodRouter::new().get(count_handler))
+        .route("/chat/create", MethodRouter::new().post(create_handler))
+        .route(
+            "/chat/invite/:room",
+            MethodRouter::new().post(invite_handler),
+        )
+        .route(
+            "/chat/secret/:room",
+            MethodRouter::new().post(secret_handler),
+        )
+        .route(
+            "/chat/kick/:room/:conn",
+            MethodRouter::new().post(kick_handler),
+        )
+        .route("/chat/close/:room", MethodRouter::new().post(close_handler))
+}
+
+/// Start or stop the bridges, called even if `chat` is disabled.
+pub async fn sync() {
+    bridge::sync();
+}
+
+/// Hourly, to remove the expired blobs in time.
+static TICKER: Lazy<Ticker> = Lazy::new(|| Ticker::new_p8(&[(-1, 30, 0)]));
+pub async fn tick() {
+    if !TICKER.tick() {
+        return;
+    }
+
+    tokio::task::spawn_blocking(|| {
+        db_clean();
+        acl::clean();
+        blob::clean();
+    })
+    .await
+    .unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn kick_lagged() {
+        db_init();
+        let room = 90002;
+        let mut feed = Feed::new(room, "a", None);
+        let mut other = Feed::new(room, "b", None);
+        for _ in 0..2 {
+            feed.next().await.unwrap(); // hello and members
+        }
+        {
+            let rooms = ROOMS.lock().unwrap();
+            let room = &rooms[&room];
+            room.members[&feed.member.conn].2.set("kicked").unwrap();
+            room.tx.send(Frame::End).ok();
+        }
+        // the `End` frame is dropped
+        for i in 0..32 {
+            publish(room, i.to_string()).unwrap();
+        }
+        let kicked = json!({ "type": "kicked" }).to_string();
+        assert!(matches!(feed.next().await, Some(Frame::Signal(0, v)) if v == kicked));
+        assert!(feed.next().await.is_none());
+
+        // the others are not affected
+        for _ in 0..2 {
+            other.next().await.unwrap();
+        }
+        assert!(matches!(other.next().await, Some(Frame::Msg(..))));
+        drop((feed, other));
+        db_delete(room);
+    }
 }
@@ -0,0 +1,118 @@
+//! WebSocket transport, `/chat/ws/:room?key=k&name=foo&last=42` to join as `foo` and replay the
+//! messages after id `42`, the `key` is required by created rooms.
+//!
+//! Frames are JSON text. From client:
+//!
+//! - `{ "type": "msg", "seq": 1, "data": "..." }` post a message, `seq` is echoed in the ack
+//! - `{ "type": "typing" }` typing indicator
+//! - `{ "type": "receipt", "id": 42 }` the message is delivered
+//!
+//! From server:
+//!
+//! - `{ "type": "hello", "conn": 7, "last": 42 }` the first frame, `conn` is this connection
+//! - `{ "type": "msg", "id": 42, "data": "..." }` a message, including the posts of SSE clients
+//! - `{ "type": "ack", "seq": 1, "id": 42 }` or `{ "type": "ack", "seq": 1, "error": "..." }`
+//! - `{ "type": "members", "members": [{ "conn": 7, "name": "foo" }] }` when joined
+//! - `{ "type": "join", "conn": 8, "name": "bar" }` and `{ "type": "leave", ... }` of others
+//! - `{ "type": "typing", "conn": 7 }` and `{ "type": "receipt", "id": 42, "conn": 7 }` of others
+//! - `{ "type": "kicked" }` or `{ "type": "closed" }` by the owner, then the connection is closed
+//! - `{ "type": "error", "error": "..." }` for malformed frames, or reached the post limit
+use super::{acl, publish, signal, throttle, Error, Feed, Frame, MSG_MAX};
+use crate::tls;
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{Path, Query};
+use axum::response::Response;
+use serde::Deserialize;
+use serde_json::json;
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientFrame {
+    Msg { seq: u64, data: String },
+    Typing,
+    Receipt { id: u64 },
+}
+
+#[derive(Deserialize)]
+pub struct WsQuery {
+    #[serde(default)]
+    key: String,
+    #[serde(default)]
+    name: String,
+    last: Option<u64>,
+}
+
+pub async fn handler(
+    Path(room): Path<u32>,
+    Query(q): Query<WsQuery>,
+    ws: WebSocketUpgrade,
+) -> Result<Response, Error> {
+    acl::authorize(room, &q.key)?;
+    let limit = MSG_MAX * 2; // the data is escaped in JSON
+    Ok(tls::upgrade_limited(ws, limit, move |ws| {
+        serve(room, q, ws)
+    }))
+}
+
+/// Handle a frame from client, returns the reply.
+fn on_frame(room: u32, conn: u64, text: &str) -> Option<String> {
+    let frame = match serde_json::from_str(text) {
+        Ok(v) => v,
+        Err(e) => return Some(json!({ "type": "error", "error": e.to_string() }).to_string()),
+    };
+    if let Err((_, e)) = throttle(room, conn) {
+        return Some(match frame {
+            ClientFrame::Msg { seq, .. } => json!({ "type": "ack", "seq": seq, "error": e }),
+            _ => json!({ "type": "error", "error": e }),
+        })
+        .map(|v| v.to_string());
+    }
+    match frame {
+        ClientFrame::Msg { seq, data } => Some(
+            match publish(room, data) {
+                Ok(id) => json!({ "type": "ack", "seq": seq, "id": id }),
+                Err(e) => json!({ "type": "ack", "seq": seq, "error": e }),
+            }
+            .to_string(),
+        ),
+        ClientFrame::Typing => {
+            let v = json!({ "type": "typing", "conn": conn });
+            signal(room, conn, v.to_string());
+            None
+        }
+        ClientFrame::Receipt { id } => {
+            let v = json!({ "type": "receipt", "id": id, "conn": conn });
+            signal(room, conn, v.to_string());
+            None
+        }
+    }
+}
+
+async fn serve(room: u32, q: WsQuery, mut ws: WebSocket) {
+    let mut feed = Feed::new(room, &q.name, q.last);
+    let conn = feed.member.conn;
+    loop {
+        let reply = tokio::select! {
+            frame = feed.next() => match frame {
+                Some(Frame::Msg(id, data)) => {
+                    json!({ "type": "msg", "id": id, "data": data }).to_string()
+                }
+                Some(Frame::Signal(_, v)) => v,
+                Some(Frame::End) => unreachable!("converted by feed"),
+                None => break,
+            },
+            msg = ws.recv() => match msg {
+                Some(Ok(Message::Text(v))) => match on_frame(room, conn, &v) {
+                    Some(v) => v,
+                    None => continue,
+                },
+                Some(Ok(Message::Close(_)) | Err(_)) | None => break,
+                Some(Ok(_)) => continue, // the pings are answered by axum
+            },
+        };
+        if ws.send(Message::Text(reply)).await.is_err() {
+            break;
+        }
+    }
+    ws.close().await.ok();
+}
@@ -1,132 +1,121 @@
-//! Auto submit JUST's health check-in form.
+//! Auto submit forms by workflows, the builtin one is JUST's health check-in.
 //!
 //! The prototype is https://github.com/kkocdko/user-scripts/blob/master/scripts/just-kit/health-check-in.js
 
+use crate::log::Filter;
 use crate::ticker::Ticker;
-use crate::utils::{fetch, fetch_json, fetch_text, log_escape, OptionResult};
-use crate::{care, db, include_page};
-use anyhow::Result;
-use axum::extract::Form;
-use axum::http::header::{HeaderName, CONTENT_TYPE, USER_AGENT};
+use crate::{care, db, log, template, units};
+use anyhow::{anyhow, bail, Result};
+use axum::extract::{Form, Path};
+use axum::http::StatusCode;
 use axum::response::{Html, IntoResponse, Redirect};
 use axum::routing::{MethodRouter, Router};
-use once_cell::sync::Lazy;
 use serde::Deserialize;
+use std::collections::HashMap;
 use std::fmt::Write;
+use std::sync::Mutex;
 mod cryptojs;
+mod workflow;
 
 fn db_init() {
     db! {"
         CREATE TABLE IF NOT EXISTS health_list
-        (id INTEGER PRIMARY KEY, password TEXT, data TEXT)
-    "}
-    .unwrap();
-    db! {"
-        CREATE TABLE IF NOT EXISTS health_log
-        (time INTEGER, id INTEGER, ret TEXT)
+        (id INTEGER PRIMARY KEY, password TEXT, data TEXT, workflow TEXT)
     "}
     .unwrap();
+    // the tables of old versions have no workflow column, means the builtin
+    db!("ALTER TABLE health_list ADD COLUMN workflow TEXT").ok();
 }
-fn db_list_set(id: u64, password: String, data: String) {
+fn db_list_set(id: u64, password: String, data: String, workflow: String) {
     db! {"
         REPLACE INTO health_list
-        VALUES (?1, ?2, ?3)
-    ", [id, password, data]}
-    .unwrap();
-}
-fn db_list_get() -> Vec<(u64, String, String)> {
-    db! {"
-        SELECT * FROM health_list
-    ", [], (0, 1, 2)}
-    .unwrap()
-}
-fn db_log_insert(id: u64, ret: String) {
-    db! {"
-        INSERT INTO health_log
-        VALUES (strftime('%s','now'), ?1, ?2)
-    ", [id, ret]}
+        VALUES (?1, ?2, ?3, ?4)
+    ", [id, password, data, workflow]}
     .unwrap();
 }
-fn db_log_get() -> Vec<(u64, u64, String)> {
+fn db_list_get() -> Vec<(u64, String, String, String)> {
     db! {"
-        SELECT * FROM health_log
-        WHERE strftime('%s','now') - time <= 3600 * 24 * 5
-        ORDER BY time DESC
-    ", [], (0, 1, 2)}
+        SELECT id, password, data, coalesce(workflow, ?) FROM health_list
+    ", [workflow::BUILTIN], (0, 1, 2, 3)}
     .unwrap()
 }
-fn db_log_clean() {
-    db! {"
-        DELETE FROM health_log
-        WHERE strftime('%s','now') - time > 3600 * 24 * 7
-    "}
-    .unwrap();
-}
 
 #[derive(Deserialize)]
 struct Member {
     id: u64,
     password: String,
     data: String,
+    /// Empty for the builtin.
+    #[serde(default)]
+    workflow: String,
 }
 
 async fn get_handler() -> impl IntoResponse {
-    const PAGE: [&str; 2] = include_page!("page.html");
-    let mut body = PAGE[0].to_string();
-    for (time, id, ret) in db_log_get() {
-        writeln!(&mut body, "{time} | {id} | {ret}").unwrap();
+    let filter = Filter {
+        level: None,
+        unit: "health".into(),
+        q: String::new(),
+    };
+    let mut log = String::new();
+    for record in log::search(&filter, 256) {
+        writeln!(&mut log, "{record}").unwrap();
     }
-    body += PAGE[1];
-    Html(body)
+    let mut o = template!("page.html").writer();
+    o.slot("log", log);
+    Html(o.finish())
 }
 
-async fn post_handler(Form(Member { id, password, data }): Form<Member>) -> Redirect {
-    db_list_set(id, password, data);
+async fn post_handler(Form(member): Form<Member>) -> Redirect {
+    let Member {
+        id,
+        password,
+        data,
+        workflow,
+    } = member;
+    let workflow = match workflow.trim() {
+        "" => workflow::BUILTIN.to_string(),
+        v => v.to_string(),
+    };
+    db_list_set(id, password, data, workflow);
     Redirect::to("/health")
 }
 
+async fn workflow_get_handler(Path(name): Path<String>) -> Result<String, StatusCode> {
+    workflow::get(&name).ok_or(StatusCode::NOT_FOUND)
+}
+
+/// Store the definition in body, empty to delete.
+async fn workflow_post_handler(
+    Path(name): Path<String>,
+    def: String,
+) -> Result<(), (StatusCode, String)> {
+    let e = |e: anyhow::Error| (StatusCode::BAD_REQUEST, e.to_string());
+    workflow::set(&name, &def).map_err(e)
+}
+
 async fn check_in() -> Result<()> {
-    db_log_insert(0, "call check_in()".into());
-    #[allow(clippy::declare_interior_mutable_const)]
-    const AUTHENTICATION: HeaderName = HeaderName::from_static("authentication"); // not AUTHORIZATION
-    const LOGIN_EXECUTION_VALUE: &str = include_str!("login_execution_value.txt");
-    const FORM_WID: &str = "a5e94ae0b0e04193bae67c86cfd6e223";
-    for (id, password, data) in db_list_get() {
-        let uri = "http://ids2.just.edu.cn/cas/login?service=http%3A%2F%2Fdc.just.edu.cn%2F%23%2F";
-        let body = format!("username={id}&password={password}&execution={LOGIN_EXECUTION_VALUE}&_eventId=submit&encrypted=true&loginType=1&submit=%E7%99%BB+%E5%BD%95");
-        let request = hyper::Request::post(uri)
-            .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
-            .header(USER_AGENT, "Chrome")
-            .body(body.into())?;
-        let r = fetch(request).await?;
-        let r = r.headers().get("location").e()?.to_str()?;
-        let ticket = r.split_once("ticket=").e()?.1.split_once('#').e()?.0;
-
-        let uri = format!("http://dc.just.edu.cn/dfi/validateLogin?ticket={ticket}&service=http%3A%2F%2Fdc.just.edu.cn%2F%23%2F");
-        let request = hyper::Request::get(uri).body(hyper::Body::empty())?;
-        let authentication = fetch_json(request, "/data/token").await?;
-
-        let uri = format!("http://dc.just.edu.cn/dfi/formOpen/saveFormView?formWid={FORM_WID}");
-        let request = hyper::Request::post(uri)
-            .header(AUTHENTICATION, &authentication)
-            .body(hyper::Body::empty())?;
-        let submit_token = fetch_json(request, "/data/submitToken").await?;
-
-        let uri = "http://dc.just.edu.cn/dfi/formData/saveFormSubmitDataEncryption";
-        let body = format! {r#"{{"dataMap":{data},"formWid":"{FORM_WID}","submitToken":"{submit_token}"}}"#};
-        let body = cryptojs::encrypt4just(body);
-        let request = hyper::Request::post(uri)
-            .header(AUTHENTICATION, &authentication)
-            .body(body.into())?;
-        let ret = log_escape(&fetch_text(request).await?);
-
-        db_log_insert(id, ret);
+    log!(Info, "call check_in()");
+    for (id, password, data, name) in db_list_get() {
+        let vars = HashMap::from([
+            ("id".to_string(), id.to_string()),
+            ("password".to_string(), password),
+            ("data".to_string(), data),
+        ]);
+        let ret = match workflow::load(&name) {
+            Ok(workflow) => workflow.run(vars).await,
+            Err(e) => Err(e),
+        };
+        match ret {
+            Ok(ret) => log!(Info, "{id} | {ret}"),
+            Err(e) => log!(Warn, "{id} | {name} | {e}"),
+        }
     }
     Ok(())
 }
 
 pub fn service() -> Router {
     db_init();
+    workflow::init();
     Router::new()
         .route(
             "/health",
@@ -144,14 +133,68 @@ pub fn service() -> Router {
                 })
                 .layer(crate::auth::auth_layer()),
         )
+        .route(
+            "/health/workflow/:name",
+            MethodRouter::new()
+                .get(workflow_get_handler)
+                .post(workflow_post_handler)
+                .layer(crate::auth::auth_layer()), // may include secrets
+        )
 }
 
-static TICKER: Lazy<Ticker> = Lazy::new(|| Ticker::new_p8(&[(6, 2, 0), (8, 2, 0)]));
+/// The check-in times in UTC+8, one `HH:MM` per line.
+pub const DEFAULT_SETTINGS: &str = "06:02\n08:02";
+
+fn schedule(settings: &str) -> Result<Vec<(i64, i64, i64)>> {
+    let mut ret = Vec::new();
+    for line in settings.lines().map(str::trim).filter(|v| !v.is_empty()) {
+        let (h, m) = line
+            .split_once(':')
+            .ok_or_else(|| anyhow!("expect HH:MM"))?;
+        let (h, m) = (h.parse()?, m.parse()?);
+        if !matches!((h, m), (0..=23, 0..=59)) {
+            bail!("invalid time {line}");
+        }
+        ret.push((h, m, 0));
+    }
+    if ret.is_empty() {
+        bail!("at least one time");
+    }
+    Ok(ret)
+}
+
+pub fn check_settings(v: &str) -> Result<()> {
+    schedule(v).map(|_| ())
+}
+
+/// Rebuilt if the settings changed.
+static TICKER: Mutex<Option<(String, Ticker)>> = Mutex::new(None);
 pub async fn tick() {
-    if !TICKER.tick() {
-        return;
+    let settings = units::settings("health");
+    {
+        let mut ticker = TICKER.lock().unwrap();
+        if !matches!(&*ticker, Some((v, _)) if v == &settings) {
+            let patterns = schedule(&settings).or_else(|_| schedule(DEFAULT_SETTINGS));
+            let patterns = patterns.unwrap();
+            *ticker = Some((settings, Ticker::new_p8(&patterns)));
+        }
+        if !ticker.as_ref().unwrap().1.tick() {
+            return;
+        }
     }
 
     care!(check_in().await).ok();
-    db_log_clean();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn page() {
+        log!(Info, "page test");
+        let html = get_handler().await.into_response();
+        let body = hyper::body::to_bytes(html.into_body()).await.unwrap();
+        assert!(String::from_utf8_lossy(&body).contains("page test"));
+    }
 }
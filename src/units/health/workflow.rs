@@ -0,0 +1,329 @@
+//! Declarative form automation, the workflows are stored in `health_workflows` table as JSON.
+//!
+//! A workflow is a sequence of HTTP steps within a `Session`. The `{{name}}` in uri, headers and
+//! body are replaced by variables: the `id`, `password` and `data` of the member, the `vars` of
+//! the workflow, and the values extracted by previous steps. Extraction sources are:
+//!
+//! - `/data/token` the field of JSON response by pointer, like `Fetch::json`
+//! - `?ticket` the query parameter of the followed redirects
+//! - empty for the whole response text
+//!
+//! The response text of the last step is the result. See `just.json` for example.
+use super::cryptojs;
+use crate::client::{json_pointer, read_text, Redirects, Session, DEFAULT_BODY_LIMIT};
+use crate::db;
+use anyhow::{anyhow, bail, Result};
+use axum::http::header::{HeaderName, HeaderValue};
+use axum::http::Method;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+fn db_init() {
+    db! {"
+        CREATE TABLE IF NOT EXISTS health_workflows
+        (name TEXT PRIMARY KEY, def TEXT)
+    "}
+    .unwrap();
+}
+fn db_set(name: &str, def: &str) {
+    db! {"
+        REPLACE INTO health_workflows
+        VALUES (?1, ?2)
+    ", [name, def]}
+    .unwrap();
+}
+fn db_get(name: &str) -> Option<(String,)> {
+    db!("SELECT def FROM health_workflows WHERE name = ?", [name], ^(0)).ok()
+}
+fn db_delete(name: &str) {
+    db!("DELETE FROM health_workflows WHERE name = ?", [name]).unwrap();
+}
+
+/// Applied to the rendered body.
+#[derive(Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Transform {
+    /// AES of crypto-js with JUST's key, see `cryptojs`.
+    Encrypt4just,
+}
+
+impl Transform {
+    fn apply(self, body: String) -> String {
+        match self {
+            Transform::Encrypt4just => cryptojs::encrypt4just(body),
+        }
+    }
+}
+
+fn default_method() -> String {
+    "GET".into()
+}
+
+#[derive(Serialize, Deserialize)]
+struct Step {
+    #[serde(default = "default_method")]
+    method: String,
+    uri: String,
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    #[serde(default)]
+    body: String,
+    transform: Option<Transform>,
+    #[serde(default)]
+    max_redirects: u32,
+    #[serde(default)]
+    retry: u32,
+    idempotent: Option<bool>,
+    /// Variable name to extraction source.
+    #[serde(default)]
+    extract: BTreeMap<String, String>,
+    /// Default headers of the following steps, rendered after extraction.
+    #[serde(default)]
+    session_headers: BTreeMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Workflow {
+    /// Default headers of all steps.
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    #[serde(default)]
+    vars: BTreeMap<String, String>,
+    steps: Vec<Step>,
+}
+
+/// Replace the `{{name}}` marks, the values are not rendered again.
+fn render(template: &str, vars: &HashMap<String, String>) -> Result<String> {
+    let mut ret = String::new();
+    let mut rest = template;
+    while let Some((before, after)) = rest.split_once("{{") {
+        let (name, after) = after
+            .split_once("}}")
+            .ok_or_else(|| anyhow!("mark is not closed"))?;
+        let v = vars.get(name.trim());
+        ret += before;
+        ret += v.ok_or_else(|| anyhow!("variable {name} not found"))?;
+        rest = after;
+    }
+    ret += rest;
+    Ok(ret)
+}
+
+fn header(k: &str, v: &str) -> Result<(HeaderName, HeaderValue)> {
+    Ok((
+        HeaderName::from_bytes(k.as_bytes())?,
+        HeaderValue::from_str(v)?,
+    ))
+}
+
+fn extract(source: &str, text: &str, redirects: Option<&Redirects>) -> Result<String> {
+    if source.is_empty() {
+        Ok(text.to_string())
+    } else if let Some(name) = source.strip_prefix('?') {
+        let v = redirects.and_then(|v| v.query(name)).map(str::to_string);
+        v.ok_or_else(|| anyhow!("query {name} not found"))
+    } else {
+        json_pointer(text, source)
+    }
+}
+
+impl Workflow {
+    fn parse(def: &str) -> Result<Self> {
+        let ret: Self = serde_json::from_str(def)?;
+        if ret.steps.is_empty() {
+            bail!("at least one step");
+        }
+        for step in &ret.steps {
+            Method::from_bytes(step.method.as_bytes())?;
+        }
+        Ok(ret)
+    }
+
+    /// Run with the variables, returns the response text of the last step.
+    pub async fn run(&self, mut vars: HashMap<String, String>) -> Result<String> {
+        for (k, v) in &self.vars {
+            vars.entry(k.clone()).or_insert_with(|| v.clone());
+        }
+        let mut session = Session::new().unit("health");
+        for (k, v) in &self.headers {
+            let (k, v) = header(k, &render(v, &vars)?)?;
+            session = session.header(k, v);
+        }
+        let mut text = String::new();
+        for (i, step) in self.steps.iter().enumerate() {
+            let step_err = |e: anyhow::Error| anyhow!("step {i}: {e}");
+            let mut request = hyper::Request::builder()
+                .method(step.method.as_str())
+                .uri(render(&step.uri, &vars).map_err(step_err)?);
+            for (k, v) in &step.headers {
+                let v = render(v, &vars).map_err(step_err)?;
+                let (k, v) = header(k, &v).map_err(step_err)?;
+                request = request.header(k, v);
+            }
+            let body = render(&step.body, &vars).map_err(step_err)?;
+            let body = match step.transform {
+                Some(transform) => transform.apply(body),
+                None => body,
+            };
+            let body = match body.is_empty() {
+                true => hyper::Body::empty(),
+                false => body.into(),
+            };
+            let request = request.body(body).map_err(|e| step_err(e.into()))?;
+            let mut fetch = session.fetch(request);
+            fetch = fetch.max_redirects(step.max_redirects).retry(step.retry);
+            if let Some(v) = step.idempotent {
+                fetch = fetch.idempotent(v);
+            }
+            let r = fetch.send().await.map_err(step_err)?;
+            let redirects = r.extensions().get::<Redirects>().cloned();
+            text = read_text(r.into_body(), DEFAULT_BODY_LIMIT)
+                .await
+                .map_err(|e| step_err(e.into()))?;
+            for (k, source) in &step.extract {
+                let v = extract(source, &text, redirects.as_ref()).map_err(step_err)?;
+                vars.insert(k.clone(), v);
+            }
+            for (k, v) in &step.session_headers {
+                let v = render(v, &vars).map_err(step_err)?;
+                let (k, v) = header(k, &v).map_err(step_err)?;
+                session = session.header(k, v);
+            }
+        }
+        Ok(text)
+    }
+}
+
+/// The builtin JUST's health check-in, it's not stored so that upgrades take effect. Copy it
+/// under another name to modify.
+pub const BUILTIN: &str = "just";
+
+fn builtin_def() -> String {
+    const LOGIN_EXECUTION_VALUE: &str = include_str!("login_execution_value.txt");
+    let mut v = Workflow::parse(include_str!("just.json")).unwrap();
+    v.vars
+        .insert("execution".into(), LOGIN_EXECUTION_VALUE.into());
+    serde_json::to_string_pretty(&v).unwrap()
+}
+
+pub fn init() {
+    db_init();
+    db_delete(BUILTIN); // stored by the previous versions
+}
+
+pub fn load(name: &str) -> Result<Workflow> {
+    let def = get(name).ok_or_else(|| anyhow!("workflow {name} not found"))?;
+    Workflow::parse(&def)
+}
+
+/// The definition text.
+pub fn get(name: &str) -> Option<String> {
+    match name {
+        BUILTIN => Some(builtin_def()),
+        _ => db_get(name).map(|v| v.0),
+    }
+}
+
+/// Check and store the definition, empty to delete.
+pub fn set(name: &str, def: &str) -> Result<()> {
+    if name == BUILTIN {
+        bail!("{BUILTIN} is builtin, store it under another name");
+    }
+    if def.trim().is_empty() {
+        db_delete(name);
+        return Ok(());
+    }
+    Workflow::parse(def)?;
+    db_set(name, def);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::extract::Query;
+    use axum::http::HeaderMap;
+    use axum::response::Redirect;
+    use axum::routing::{get, Router};
+    use serde_json::json;
+    use std::net::SocketAddr;
+
+    fn vars(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        let pairs = pairs.iter().map(|(k, v)| (k.to_string(), v.to_string()));
+        pairs.collect()
+    }
+
+    #[test]
+    fn builtin() {
+        let v = load(BUILTIN).unwrap();
+        assert_eq!(v.steps.len(), 4);
+        assert!(v.vars.contains_key("execution"));
+    }
+
+    #[test]
+    fn render_marks() {
+        let vars = vars(&[("a", "1"), ("b", "{{a}}")]);
+        assert_eq!(render("x{{a}}y{{ b }}", &vars).unwrap(), "x1y{{a}}");
+        assert_eq!(render("no marks", &vars).unwrap(), "no marks");
+        let e = render("{{c}}", &vars).unwrap_err();
+        assert_eq!(e.to_string(), "variable c not found");
+        let e = render("{{a", &vars).unwrap_err();
+        assert_eq!(e.to_string(), "mark is not closed");
+    }
+
+    #[test]
+    fn extract_sources() {
+        let text = r#"{ "data": { "token": "t", "n": 1 } }"#;
+        let redirects = Redirects(vec!["http://a.com/b?ticket=x&c=1".parse().unwrap()]);
+        assert_eq!(extract("?ticket", text, Some(&redirects)).unwrap(), "x");
+        assert!(extract("?none", text, Some(&redirects)).is_err());
+        assert!(extract("?ticket", text, None).is_err());
+        assert_eq!(extract("/data/token", text, None).unwrap(), "t");
+        assert_eq!(extract("/data/n", text, None).unwrap(), "1");
+        assert!(extract("/data/none", text, None).is_err());
+        assert_eq!(extract("", text, None).unwrap(), text);
+    }
+
+    #[tokio::test]
+    async fn run() {
+        let app = Router::new()
+            .route("/login", get(|| async { Redirect::to("/ticket?ticket=T") }))
+            .route(
+                "/ticket",
+                get(|| async { r#"{ "data": { "token": "tk" } }"# }),
+            )
+            .route(
+                "/check",
+                get(
+                    |headers: HeaderMap, Query(q): Query<HashMap<String, String>>| async move {
+                        let v = headers.get("authentication").unwrap().to_str().unwrap();
+                        format!("{v} {}", q["t"])
+                    },
+                ),
+            );
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr: SocketAddr = listener.local_addr().unwrap();
+        let server = axum::Server::from_tcp(listener).unwrap();
+        tokio::spawn(server.serve(app.into_make_service()));
+
+        let def = json!({
+            "vars": { "base": format!("http://{addr}") },
+            "steps": [
+                {
+                    "uri": "{{base}}/login",
+                    "max_redirects": 1,
+                    "extract": { "ticket": "?ticket", "token": "/data/token" },
+                    "session_headers": { "authentication": "{{token}}" }
+                },
+                { "uri": "{{base}}/check?t={{ticket}}" }
+            ]
+        });
+        let workflow = Workflow::parse(&def.to_string()).unwrap();
+        assert_eq!(workflow.run(HashMap::new()).await.unwrap(), "tk T");
+
+        let def = json!({ "steps": [{ "uri": "http://{{missing}}/" }] });
+        let workflow = Workflow::parse(&def.to_string()).unwrap();
+        let e = workflow.run(HashMap::new()).await.unwrap_err();
+        assert_eq!(e.to_string(), "step 0: variable missing not found");
+    }
+}
@@ -1,12 +1,15 @@
 //! Provide server info.
 
-use crate::include_page;
-use crate::utils::fetch_text;
+use crate::client::{breaker, Fetch};
+use crate::remote::{ClientIp, Peer};
+use crate::template;
+use axum::extract::Extension;
 use axum::http::header::{CACHE_CONTROL, REFRESH};
 use axum::response::{Html, IntoResponse};
 use axum::routing::{MethodRouter, Router};
 use std::sync::atomic::{AtomicI64, Ordering};
 use std::time::{Duration, Instant, UNIX_EPOCH};
+use tokio::time::error::Elapsed;
 
 // use once_cell::sync::Lazy;
 // static SYS_VER: Lazy<String> = Lazy::new(|| {
@@ -24,19 +27,24 @@ static LATENCY_ALIYUN: AtomicI64 = AtomicI64::new(0);
 
 async fn refresh(uri: &str, data: &AtomicI64) {
     let instant = Instant::now();
-    match tokio::time::timeout(Duration::from_secs(3), fetch_text(uri)).await {
-        Ok(Ok(_)) => data.store(instant.elapsed().as_millis() as _, Ordering::SeqCst),
-        Ok(Err(_)) => data.store(-9, Ordering::SeqCst), // network error
-        Err(_) => data.store(-7, Ordering::SeqCst),     // timeout
+    let fetch = Fetch::new(uri).unit("info").decompress(false); // body is not cared
+    match fetch.timeout(Duration::from_secs(3)).text().await {
+        Ok(_) => data.store(instant.elapsed().as_millis() as _, Ordering::SeqCst),
+        Err(e) if e.is::<Elapsed>() => data.store(-7, Ordering::SeqCst), // timeout
+        Err(_) => data.store(-9, Ordering::SeqCst),                      // network error
     };
 }
 
-async fn get_handler() -> impl IntoResponse {
-    const PAGE: [&str; 2] = include_page!("page.html");
+fn render(info: String) -> Html<String> {
+    let mut o = template!("page.html").writer();
+    o.slot("info", info);
+    Html(o.finish())
+}
 
+async fn get_handler(ClientIp(ip): ClientIp, peer: Option<Extension<Peer>>) -> impl IntoResponse {
     let now = UNIX_EPOCH.elapsed().unwrap().as_secs() as i64;
 
-    let mut o = PAGE[0].to_string();
+    let mut o = String::new();
 
     o += concat!(
         env!("CARGO_PKG_NAME"),
@@ -51,6 +59,14 @@ async fn get_handler() -> impl IntoResponse {
     o += &(now - START_TIME.load(Ordering::SeqCst)).to_string();
     o += " s\n";
 
+    o += "client ip : ";
+    o += &ip.to_string();
+    if let Some(Extension(Peer { addr, .. })) = peer.filter(|v| v.addr.ip() != ip) {
+        o += " via ";
+        o += &addr.ip().to_string();
+    }
+    o += "\n";
+
     if now - LAST_REFRESH.load(Ordering::SeqCst) > 5 {
         tokio::spawn(async move {
             LAST_REFRESH.store(now, Ordering::SeqCst);
@@ -59,7 +75,7 @@ async fn get_handler() -> impl IntoResponse {
                 refresh("http://aliyun.com/404", &LATENCY_ALIYUN),
             );
         });
-        return ([(REFRESH, "1")], Html(o + PAGE[1]));
+        return ([(REFRESH, "1")], render(o));
     }
 
     o += "server <-> baidu : ";
@@ -82,9 +98,16 @@ async fn get_handler() -> impl IntoResponse {
         }
     }
 
-    o += PAGE[1];
+    o += "circuit breakers : ";
+    match breaker::status() {
+        v if v.is_empty() => o += "all closed\n",
+        v => {
+            o += "\n";
+            o += &v;
+        }
+    }
 
-    ([(CACHE_CONTROL, "no-store")], Html(o))
+    ([(CACHE_CONTROL, "no-store")], render(o))
 }
 
 pub fn service() -> Router {
@@ -96,3 +119,13 @@ pub fn service() -> Router {
         .route("/info", MethodRouter::new().get(get_handler))
         .route("/info/p", MethodRouter::new().get(|| async { "pong" })) // the "/ping" cause error?
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn page() {
+        assert!(render("uptime : 1 s".into()).0.contains("uptime : 1 s"));
+    }
+}
@@ -1,148 +1,184 @@
 //! Collections of my favorite news source.
 
+use crate::client::Fetch;
 use crate::ticker::Ticker;
-use crate::utils::{fetch_text, OptionResult};
-use crate::{care, include_page};
-use anyhow::Result;
-use axum::body::Bytes;
-use axum::http::header::{HeaderName, HeaderValue};
-use axum::http::header::{CACHE_CONTROL, CONTENT_ENCODING, EXPIRES, REFRESH};
-use axum::response::Html;
+use crate::utils::CompressedPage;
+use crate::{care, template, units};
+use anyhow::{anyhow, bail, Result};
+use axum::http::header::{HeaderMap, HeaderValue};
+use axum::http::header::{CACHE_CONTROL, EXPIRES, REFRESH};
 use axum::routing::{MethodRouter, Router};
 use once_cell::sync::Lazy;
-use std::io::Read as _;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, SystemTime};
 
-fn generate(mut i: &str, o: &mut String, mut limit: usize) -> Result<()> {
-    while let Some(mut p) = i.split_once("<item>") {
-        limit -= 1;
-
-        *o += "\n<details>\n";
-
-        // title
-        i = p.1.split_once("<![CDATA[").e()?.1;
-        p = i.split_once("]]>").e()?;
-        *o += "<summary>";
-        *o += p.0;
-        *o += "</summary>\n";
-
-        // content
-        i = p.1.split_once("<![CDATA[").e()?.1;
-        p = i.split_once("]]>").e()?;
-        *o += "<section>";
-        let break_marks = [
-            "br>", "p>", "p ", "/p>", "div>", "div ", "/div>", "li>", "li ", "/li>",
-        ];
-        while let Some(v) = p.0.split_once('<') {
-            p.0 = v.1.split_once('>').e()?.1;
-            let c = v.0.trim();
-            if !c.is_empty() {
-                *o += c;
-            }
-            if !o.ends_with("<br>") {
-                for mark in break_marks {
-                    if v.1.starts_with(mark) {
-                        *o += "<br>";
-                        break;
+struct Item<'a> {
+    title: &'a str,
+    /// Text nodes of the html content, with `<br>` between blocks.
+    content: String,
+    link: &'a str,
+}
+
+/// Parse items from RSS, stop at the first broken item.
+fn parse(mut i: &str, limit: usize) -> Vec<Item<'_>> {
+    let mut items = Vec::new();
+    while items.len() < limit {
+        let mut item = || {
+            let mut p = i.split_once("<item>")?;
+
+            // title
+            i = p.1.split_once("<![CDATA[")?.1;
+            p = i.split_once("]]>")?;
+            let title = p.0;
+
+            // content, only `<br>` is kept, the other tags are dropped
+            i = p.1.split_once("<![CDATA[")?.1;
+            p = i.split_once("]]>")?;
+            let mut content = String::new();
+            let break_marks = [
+                "br>", "p>", "p ", "/p>", "div>", "div ", "/div>", "li>", "li ", "/li>",
+            ];
+            while let Some(v) = p.0.split_once('<') {
+                p.0 = v.1.split_once('>')?.1;
+                let c = v.0.trim();
+                if !c.is_empty() {
+                    content += c;
+                }
+                if !content.ends_with("<br>") {
+                    for mark in break_marks {
+                        if v.1.starts_with(mark) {
+                            content += "<br>";
+                            break;
+                        }
                     }
                 }
             }
-        }
-        *o += "</section>\n";
 
-        // link
-        i = p.1.split_once("<link>").e()?.1;
-        p = i.split_once("</link>").e()?;
-        *o += "<a href=\"";
-        *o += p.0;
-        *o += "\">[ Original Link ]</a>\n";
+            // link
+            i = p.1.split_once("<link>")?.1;
+            p = i.split_once("</link>")?;
+            let link = p.0;
 
-        *o += "</details>\n";
-        i = p.1;
-
-        if limit == 0 {
-            break;
+            i = p.1;
+            Some(Item {
+                title,
+                content,
+                link,
+            })
+        };
+        match item() {
+            Some(v) => items.push(v),
+            None => break,
         }
     }
-    *o += "\n<br>\n";
-    Ok(())
+    items
 }
 
-type Res = ([(HeaderName, HeaderValue); 2], Html<Bytes>);
-
-const PAGE: [&str; 2] = include_page!("page.html");
+fn render(notice: Option<&str>, feeds: &[Vec<Item>]) -> String {
+    let mut o = template!("page.html").writer();
+    o.section("notice", notice, |o, v| {
+        o.slot("text", v);
+    });
+    o.section("feed", feeds, |o, items| {
+        o.section("item", items, |o, item| {
+            o.slot("title", item.title);
+            // safe because all '<' are consumed as tags, except the inserted `<br>`
+            o.raw("content", &item.content);
+            o.slot("link", item.link);
+        });
+    });
+    o.finish()
+}
 
-static CACHE: Lazy<Mutex<Res>> = Lazy::new(|| {
-    let body = format!("{}<h2>Magazine is generating ...</h2>{}", PAGE[0], PAGE[1]);
+static CACHE: Lazy<Mutex<(HeaderMap, Arc<CompressedPage>)>> = Lazy::new(|| {
+    let body = render(Some("Magazine is generating ..."), &[]);
+    let mut headers = HeaderMap::new();
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    headers.insert(REFRESH, HeaderValue::from_static("2"));
     // with small data, Mutex seems faster than RwLock
-    Mutex::new((
-        [
-            (CACHE_CONTROL, HeaderValue::from_static("no-store")),
-            (REFRESH, HeaderValue::from_static("2")),
-        ],
-        Html(Bytes::from(body)),
-    ))
+    Mutex::new((headers, Arc::new(CompressedPage::new(body))))
 });
 
+/// The RSSHub routes and item limits, one `path limit` per line.
+pub const DEFAULT_SETTINGS: &str = "\
+/leetcode/dailyquestion/solution/en 5
+/bbc 9
+/zhihu/daily 9
+/oschina/news/industry 9
+/1point3acres/post/hot3 9
+/rustcc/jobs 5";
+
+fn feeds(settings: &str) -> Result<Vec<(String, usize)>> {
+    let mut ret = Vec::new();
+    for line in settings.lines().map(str::trim).filter(|v| !v.is_empty()) {
+        let (path, limit) = line
+            .split_once(' ')
+            .ok_or_else(|| anyhow!("expect path limit"))?;
+        let limit = limit.trim().parse()?;
+        if !path.starts_with('/') || !(1..=50).contains(&limit) {
+            bail!("invalid feed {line}");
+        }
+        ret.push((path.to_string(), limit));
+    }
+    Ok(ret)
+}
+
+pub fn check_settings(v: &str) -> Result<()> {
+    feeds(v).map(|_| ())
+}
+
 async fn refresh() -> Result<()> {
     let expires = httpdate::fmt_http_date(SystemTime::now() + Duration::from_secs(3600));
-    macro_rules! rss {
-        ($p:expr) => {{
-            /*
-            https://rsshub.app
-            https://rsshub.uneasy.win
-            https://rsshub.rssforever.com
-            https://rsshub.moeyy.cn
-            https://rss.itggg.cn
-            */
-            fetch_text(concat!("https://rsshub.rssforever.com", $p))
-        }};
+    /*
+    https://rsshub.app
+    https://rsshub.uneasy.win
+    https://rsshub.rssforever.com
+    https://rsshub.moeyy.cn
+    https://rss.itggg.cn
+    */
+    let mut tasks = Vec::new();
+    for (path, limit) in feeds(&units::settings("magazine"))? {
+        let fetch = Fetch::new(&format!("https://rsshub.rssforever.com{path}"))
+            .unit("magazine")
+            .max_redirects(3)
+            .retry(2)
+            .body_limit(4 << 20)
+            .cache(true)
+            .connect_timeout(Duration::from_secs(5))
+            .timeout(Duration::from_secs(60));
+        tasks.push((tokio::spawn(fetch.text()), limit));
+    }
+    let mut r = Vec::new();
+    for (task, limit) in tasks {
+        r.push((care!(task.await?).unwrap_or_default(), limit));
     }
-    let r = tokio::join!(
-        rss!("/leetcode/dailyquestion/solution/en"),
-        rss!("/bbc"),
-        rss!("/zhihu/daily"),
-        rss!("/oschina/news/industry"),
-        rss!("/1point3acres/post/hot3"),
-        rss!("/rustcc/jobs"),
-    );
-    let o = tokio::task::spawn_blocking(move || {
-        let mut o = PAGE[0].to_string();
-        r.0.map(|v| generate(&v, &mut o, 5)).ok();
-        r.1.map(|v| generate(&v, &mut o, 9)).ok();
-        r.2.map(|v| generate(&v, &mut o, 9)).ok();
-        r.3.map(|v| generate(&v, &mut o, 9)).ok();
-        r.4.map(|v| generate(&v, &mut o, 9)).ok();
-        r.5.map(|v| generate(&v, &mut o, 5)).ok();
-        o += PAGE[1];
-        let mut buf = Vec::new();
-        let mut enc = flate2::read::GzEncoder::new(o.as_bytes(), Default::default());
-        enc.read_to_end(&mut buf).unwrap();
-        buf
+    let page = tokio::task::spawn_blocking(move || {
+        let feeds = r
+            .iter()
+            .map(|(v, limit)| parse(v, *limit))
+            .collect::<Vec<_>>();
+        let page = CompressedPage::new(render(None, &feeds));
+        page.warm(); // compress here, not in the first request
+        page
     })
     .await?;
-    *CACHE.lock().unwrap() = (
-        [
-            (
-                EXPIRES,
-                HeaderValue::from_maybe_shared(Bytes::from(expires)).unwrap(),
-            ),
-            (CONTENT_ENCODING, HeaderValue::from_static("gzip")),
-        ],
-        Html(Bytes::from(o)), // `bytes::Bytes` is cheaper than `Vec<u8>` on clone
-    );
+    let mut headers = HeaderMap::new();
+    headers.insert(EXPIRES, HeaderValue::from_str(&expires)?);
+    *CACHE.lock().unwrap() = (headers, Arc::new(page));
     Ok(())
 }
 
 pub fn service() -> Router {
-    tokio::spawn(async {
-        care!(refresh().await).ok();
-    });
+    if units::enabled("magazine") {
+        tokio::spawn(async {
+            care!(refresh().await).ok();
+        });
+    }
     Router::new().route(
         "/magazine",
-        MethodRouter::new().get(|| async {
-            CACHE.lock().unwrap().to_owned() // just clone some AtomicPtr inner
+        MethodRouter::new().get(|req_headers: HeaderMap| async move {
+            let (headers, page) = CACHE.lock().unwrap().clone(); // just clone some Arc inner
+            (headers, page.respond(&req_headers))
         }),
     )
 }
@@ -155,3 +191,20 @@ pub async fn tick() {
 
     care!(refresh().await).ok();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn page() {
+        let item = Item {
+            title: "a & b",
+            content: "c<br>d".into(),
+            link: "https://e.com",
+        };
+        let html = render(Some("generating"), &[vec![item]]);
+        assert!(html.contains("generating"));
+        assert!(html.contains("a &amp; b") && html.contains("c<br>d"));
+    }
+}
@@ -1,3 +1,6 @@
+//! The units, their enabled states and settings are stored in `units` table.
+//!
+//! A disabled unit responds `404` like unmounted, and its ticks are paused.
 pub mod admin;
 pub mod chat;
 pub mod health;
@@ -6,4 +9,159 @@ pub mod magazine;
 pub mod paste;
 // pub mod paste_next;
 pub mod qqbot;
-// pub mod record;
+pub mod record;
+
+use crate::{database, db, limit};
+use anyhow::{anyhow, bail, Result};
+use axum::http::{Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Router;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::future::Future;
+use std::sync::Mutex;
+
+fn db_init() {
+    db! {"
+        CREATE TABLE IF NOT EXISTS units
+        (name TEXT PRIMARY KEY, enabled INTEGER, settings TEXT)
+    "}
+    .unwrap();
+    // the notify groups of qqbot moved into settings
+    database::migrate("units_qqbot_groups", |db| {
+        if !database::table_exists(db, "qqbot_groups")? {
+            return Ok(());
+        }
+        db.execute_batch(
+            "
+            INSERT INTO units
+            SELECT 'qqbot', 1, group_concat(group_id, char(10)) FROM qqbot_groups
+            WHERE true
+            ON CONFLICT (name) DO UPDATE SET settings = coalesce(settings, excluded.settings);
+            DROP TABLE qqbot_groups;
+            ",
+        )
+    })
+    .unwrap();
+}
+fn db_set(name: &str, enabled: bool, settings: Option<&str>) {
+    db! {"
+        REPLACE INTO units
+        VALUES (?1, ?2, ?3)
+    ", [name, enabled, settings]}
+    .unwrap();
+}
+fn db_get_all() -> Vec<(String, bool, Option<String>)> {
+    db!("SELECT * FROM units", [], (0, 1, 2)).unwrap()
+}
+
+pub const UNITS: [&str; 8] = [
+    "admin", "chat", "health", "info", "magazine", "paste", "qqbot", "record",
+];
+
+/// The default value and checker of settings, for units which have.
+type SettingsHook = (&'static str, &'static str, fn(&str) -> Result<()>);
+const SETTINGS: [SettingsHook; 4] = [
+    ("chat", chat::DEFAULT_SETTINGS, chat::check_settings),
+    ("health", health::DEFAULT_SETTINGS, health::check_settings),
+    (
+        "magazine",
+        magazine::DEFAULT_SETTINGS,
+        magazine::check_settings,
+    ),
+    ("qqbot", qqbot::DEFAULT_SETTINGS, qqbot::check_settings),
+];
+
+/// The enabled state and settings, `None` means default.
+type State = (bool, Option<String>);
+static STATES: Lazy<Mutex<HashMap<String, State>>> = Lazy::new(|| {
+    db_init();
+    let states = db_get_all().into_iter().map(|(k, e, s)| (k, (e, s)));
+    Mutex::new(states.collect())
+});
+
+fn unit_name(name: &str) -> Result<&'static str> {
+    let name = UNITS.iter().find(|&&v| v == name);
+    name.copied().ok_or_else(|| anyhow!("unknown unit"))
+}
+
+/// Returns `true` if the unit is enabled, the default.
+pub fn enabled(name: &str) -> bool {
+    STATES.lock().unwrap().get(name).is_none_or(|v| v.0)
+}
+
+/// The settings text, or the default value.
+pub fn settings(name: &str) -> String {
+    let default = SETTINGS.iter().find(|v| v.0 == name).map(|v| v.1);
+    let states = STATES.lock().unwrap();
+    let v = states.get(name).and_then(|v| v.1.as_deref());
+    v.or(default).unwrap_or_default().to_string()
+}
+
+pub fn set_enabled(name: &str, enabled: bool) -> Result<()> {
+    let name = unit_name(name)?;
+    if name == "admin" && !enabled {
+        bail!("admin can not be disabled");
+    }
+    let mut states = STATES.lock().unwrap();
+    let state = states.entry(name.to_string()).or_insert((true, None));
+    state.0 = enabled;
+    db_set(name, state.0, state.1.as_deref());
+    Ok(())
+}
+
+/// Set the settings text, empty to reset as default.
+pub fn set_settings(name: &str, v: &str) -> Result<()> {
+    let name = unit_name(name)?;
+    let hook = SETTINGS.iter().find(|v| v.0 == name);
+    let (_, _, check) = hook.ok_or_else(|| anyhow!("{name} has no settings"))?;
+    let v = Some(v.trim()).filter(|v| !v.is_empty());
+    if let Some(v) = v {
+        check(v)?;
+    }
+    let mut states = STATES.lock().unwrap();
+    let state = states.entry(name.to_string()).or_insert((true, None));
+    state.1 = v.map(String::from);
+    db_set(name, state.0, state.1.as_deref());
+    Ok(())
+}
+
+/// Display the enabled states, one `name on|off` per line.
+pub fn status() -> String {
+    let mut ret = String::new();
+    for name in UNITS {
+        let state = if enabled(name) { "on" } else { "off" };
+        writeln!(ret, "{name} {state}").unwrap();
+    }
+    ret
+}
+
+/// Mount the unit with rate limit, and respond `404` if disabled.
+///
+/// # Example
+///
+/// ```
+/// let app = Router::new().merge(units::mount("chat", units::chat::service()));
+/// ```
+pub fn mount(name: &'static str, router: Router) -> Router {
+    let router = router.layer(axum::middleware::from_fn(move |req, next| {
+        gate(name, req, next)
+    }));
+    limit::group(name, router)
+}
+
+async fn gate<B>(name: &'static str, req: Request<B>, next: Next<B>) -> Response {
+    match enabled(name) {
+        true => next.run(req).await,
+        false => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+/// Run the tick of unit if enabled.
+pub async fn tick(name: &str, tick: impl Future<Output = ()>) {
+    if enabled(name) {
+        tick.await;
+    }
+}
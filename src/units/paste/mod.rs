@@ -1,6 +1,6 @@
 //! Online clipboard.
 
-use crate::{db, include_page};
+use crate::{database, db, template};
 use axum::extract::{Form, Path};
 use axum::response::{Html, Redirect};
 use axum::routing::MethodRouter;
@@ -13,6 +13,16 @@ fn db_init() {
         (id INTEGER PRIMARY KEY AUTOINCREMENT, data BLOB)
     "}
     .unwrap();
+    // the html escaping moved from storing to rendering, see `template!`
+    database::migrate("paste_unescape", |db| {
+        db.execute_batch(
+            r#"
+            UPDATE paste SET data = replace(replace(replace(replace(replace(
+                data, '&lt;', '<'), '&gt;', '>'), '&quot;', '"'), '&#x27;', ''''), '&amp;', '&')
+            "#,
+        )
+    })
+    .unwrap();
 }
 fn db_insert(data: &str) -> u64 {
     db! {"
@@ -36,15 +46,12 @@ fn db_get(id: u64) -> Option<(String,)> {
     .ok()
 }
 
-fn escape(v: &str) -> String {
-    askama_escape::escape(v, askama_escape::Html).to_string()
-}
-
 async fn read(id: Option<u64>) -> Html<String> {
     let value = id.and_then(db_get);
     let value = value.unwrap_or_else(|| ("New entry".to_string(),));
-    const PAGE: [&str; 2] = include_page!("page.html");
-    Html([PAGE[0], &value.0, PAGE[1]].join(""))
+    let mut o = template!("page.html").writer();
+    o.slot("value", value.0);
+    Html(o.finish())
 }
 
 #[derive(Deserialize)]
@@ -53,12 +60,12 @@ struct Data {
 }
 
 async fn insert(form: Form<Data>) -> Redirect {
-    let id = db_insert(&escape(&form.value));
+    let id = db_insert(&form.value);
     Redirect::to(&format!("/paste/{id}"))
 }
 
 async fn update((Path(id), form): (Path<u64>, Form<Data>)) -> Redirect {
-    db_update(id, &escape(&form.value));
+    db_update(id, &form.value);
     Redirect::to(&format!("/paste/{id}"))
 }
 
@@ -76,3 +83,13 @@ pub fn service() -> Router {
                 .post(update),
         )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn page() {
+        assert!(read(None).await.0.contains("New entry"));
+    }
+}
@@ -584,7 +584,9 @@ mod misc {
 
     impl Op<'static> {
         pub fn init(&mut self) -> Result<Op, ()> {
-            let Op::Uninit { req } = self else { unreachable!() };
+            let Op::Uninit { req } = self else {
+                unreachable!()
+            };
             let mut body = Body::empty(); // TODO: optimize unnecessary body extact
             swap(req.body_mut(), &mut body);
             let headers = req.headers();
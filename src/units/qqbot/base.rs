@@ -1,44 +1,31 @@
 //! Provide login, token storage and other low-level functions.
 
 use super::gen_reply;
-use crate::utils::log_escape;
-use crate::{care, db, include_page};
-use anyhow::Result;
+use crate::log::Filter;
+use crate::units::{self, chat};
+use crate::{care, db, log, template};
+use anyhow::{bail, Result};
 use axum::body::Bytes;
 use axum::extract::RawQuery;
 use axum::response::Html;
 use once_cell::sync::Lazy;
-use ricq::client::{Connector as _, DefaultConnector, NetworkStatus};
+use ricq::client::{Connector as _, DefaultConnector, NetworkStatus, Token};
 use ricq::handler::QEvent;
 use ricq::msg::elem::RQElem;
 use ricq::msg::MessageChain;
 use ricq::structs::GroupMessage;
 use ricq::{Client, Device, LoginResponse, Protocol, QRCodeState};
+use std::fmt::Write;
 use std::future::Future;
 use std::pin::Pin;
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, UNIX_EPOCH};
 
-macro_rules! push_log {
-    ($fmt:literal $(, $($arg:tt)+)?) => {{
-        let now = UNIX_EPOCH.elapsed().unwrap().as_secs();
-        push_log_(&format!(concat!("{} | ", $fmt), now, $($($arg)+)?));
-    }}
-}
-fn push_log_(v: &str) {
-    let mut log = LOG.lock().unwrap();
-    if log.len() >= 128 {
-        log.drain(..32);
-    }
-    log.push(log_escape(v));
-}
-
 const K_DEVICE: &str = "device_json";
 const K_TOKEN: &str = "token_json";
 
 fn db_init() {
     db!("CREATE TABLE qqbot_cfg (k TEXT PRIMARY KEY, v BLOB)").ok();
-    db!("CREATE TABLE qqbot_groups (group_id INTEGER PRIMARY KEY)").ok();
 }
 fn db_cfg_set(k: &str, v: Vec<u8>) {
     db!("REPLACE INTO qqbot_cfg VALUES (?1, ?2)", [k, v]).unwrap();
@@ -49,14 +36,15 @@ fn db_cfg_get(k: &str) -> Option<(Vec<u8>,)> {
 fn db_cfg_get_text(k: &str) -> Option<String> {
     Some(String::from_utf8(db_cfg_get(k)?.0).unwrap())
 }
-fn db_groups_get() -> Vec<(i64,)> {
-    db!("SELECT * FROM qqbot_groups", [], (0)).unwrap()
-}
-pub fn db_groups_insert(group_id: i64) {
-    db!("REPLACE INTO qqbot_groups VALUES (?)", [group_id]).unwrap();
-}
-pub fn _db_groups_delete(group_id: i64) -> bool {
-    db!("DELETE FROM qqbot_groups WHERE group_id = ?", [group_id]).is_ok()
+
+/// Check the value of config before storing, used by admin console.
+pub fn check_cfg(k: &str, v: &[u8]) -> Result<()> {
+    match k {
+        K_DEVICE => drop(serde_json::from_slice::<Device>(v)?),
+        K_TOKEN => drop(serde_json::from_slice::<Token>(v)?),
+        _ => {}
+    }
+    Ok(())
 }
 
 pub async fn post_handler(q: RawQuery, body: Bytes) {
@@ -66,14 +54,18 @@ pub async fn post_handler(q: RawQuery, body: Bytes) {
 }
 
 pub async fn get_handler() -> Html<String> {
-    const PAGE: [&str; 2] = include_page!("page.html");
-    let mut body = PAGE[0].to_string();
-    for line in LOG.lock().unwrap().iter().rev() {
-        body += line;
-        body += "\n";
+    let filter = Filter {
+        level: None,
+        unit: "qqbot".into(),
+        q: String::new(),
+    };
+    let mut log = String::new();
+    for record in log::search(&filter, 128) {
+        writeln!(log, "{record}").unwrap();
     }
-    body += PAGE[1];
-    Html(body)
+    let mut o = template!("page.html").writer();
+    o.slot("log", log);
+    Html(o.finish())
 }
 
 pub fn get_login_qr() -> Vec<u8> {
@@ -81,10 +73,9 @@ pub fn get_login_qr() -> Vec<u8> {
     QR.lock().unwrap().clone()
 }
 
-static LOG: Mutex<Vec<String>> = Mutex::new(Vec::new());
 static QR: Mutex<Vec<u8>> = Mutex::new(Vec::new());
 static CLIENT: Lazy<Arc<Client>> = Lazy::new(|| {
-    push_log!("init client");
+    log!(Info, "init client");
     db_init();
     let device = match db_cfg_get_text(K_DEVICE) {
         Some(v) => serde_json::from_str(&v).unwrap(),
@@ -104,23 +95,23 @@ static CLIENT: Lazy<Arc<Client>> = Lazy::new(|| {
         loop {
             tokio::select! {
                 _ = async {
-                    push_log!("try to connect");
+                    log!(Info, "try to connect");
                     let stream = DefaultConnector.connect(&CLIENT).await?;
                     CLIENT.start(stream).await;
-                    push_log!("offline, fn start returned");
+                    log!(Warn, "offline, fn start returned");
                     anyhow::Ok(())
                 } => {}
                 _ = async {
                     launch().await?;
                     CLIENT.do_heartbeat().await;
-                    push_log!("offline, fn do_heartbeat returned");
+                    log!(Warn, "offline, fn do_heartbeat returned");
                     anyhow::Ok(())
                 } => {}
             };
             CLIENT.stop(NetworkStatus::NetworkOffline);
             let now = UNIX_EPOCH.elapsed().unwrap().as_secs();
             if now - last < 60 {
-                push_log!("reconnection was stopped, overfrequency");
+                log!(Warn, "reconnection was stopped, overfrequency");
                 return;
             }
             last = now;
@@ -136,7 +127,7 @@ async fn launch() -> Result<()> {
         tokio::time::sleep(Duration::from_millis(200)).await;
     }
     tokio::time::sleep(Duration::from_millis(200)).await;
-    push_log!("server connected");
+    log!(Info, "server connected");
 
     // # Tips about Login
     // 1. Run on local host, login by qrcode.
@@ -145,38 +136,38 @@ async fn launch() -> Result<()> {
     if let Some(v) = db_cfg_get_text(K_TOKEN) {
         let token = serde_json::from_str(&v)?;
         CLIENT.token_login(token).await?;
-        push_log!("login by token");
+        log!(Info, "login by token");
     } else {
         let mut qr_resp = CLIENT.fetch_qrcode().await?;
         let mut img_sig = Vec::new();
         loop {
             match qr_resp {
                 QRCodeState::ImageFetch(inner) => {
-                    push_log!("qrcode fetched");
+                    log!(Info, "qrcode fetched");
                     *QR.lock().unwrap() = inner.image_data.to_vec();
                     img_sig = inner.sig.to_vec();
                 }
                 QRCodeState::Timeout => {
-                    push_log!("qrcode timeout");
+                    log!(Info, "qrcode timeout");
                     qr_resp = CLIENT.fetch_qrcode().await?;
                     continue;
                 }
                 QRCodeState::Confirmed(inner) => {
-                    push_log!("qrcode confirmed");
+                    log!(Info, "qrcode confirmed");
                     let login_resp = CLIENT
                         .qrcode_login(&inner.tmp_pwd, &inner.tmp_no_pic_sig, &inner.tgt_qr)
                         .await?;
                     if let LoginResponse::DeviceLockLogin { .. } = login_resp {
                         CLIENT.device_lock_login().await?;
                     }
-                    push_log!("login by qrcode");
+                    log!(Info, "login by qrcode");
                     let token = serde_json::to_string(&CLIENT.gen_token().await)?;
                     db_cfg_set(K_TOKEN, token.into_bytes());
                     break;
                 }
-                QRCodeState::WaitingForScan => push_log!("qrcode waiting for scan"),
-                QRCodeState::WaitingForConfirm => push_log!("qrcode waiting for confirm"),
-                QRCodeState::Canceled => push_log!("qrcode canceled"),
+                QRCodeState::WaitingForScan => log!(Info, "qrcode waiting for scan"),
+                QRCodeState::WaitingForConfirm => log!(Info, "qrcode waiting for confirm"),
+                QRCodeState::Canceled => log!(Info, "qrcode canceled"),
             }
             tokio::time::sleep(Duration::from_secs(1)).await;
             qr_resp = CLIENT.query_qrcode_result(&img_sig).await?;
@@ -199,6 +190,14 @@ async fn on_event(event: QEvent) -> Result<()> {
     static RECENT: Mutex<Vec<GroupMessage>> = Mutex::new(Vec::new());
     match event {
         QEvent::GroupMessage(e) => {
+            if e.inner.from_uin != CLIENT.uin().await {
+                let sender = match e.inner.group_card.is_empty() {
+                    true => e.inner.from_uin.to_string(),
+                    false => e.inner.group_card.clone(),
+                };
+                let text = e.inner.elements.to_string();
+                chat::incoming(e.inner.group_code, &format!("qq:{sender}"), &text);
+            }
             if matches!(
                 e.inner.elements.0.get(0).map(|v| RQElem::from(v.clone())),
                 Some(RQElem::At(v)) if v.target == CLIENT.uin().await
@@ -220,14 +219,15 @@ async fn on_event(event: QEvent) -> Result<()> {
             if len >= 64 && len % 8 == 0 {
                 // messages sent 2 minutes ago cannot be recalled
                 recent.retain(|v| time - v.time <= 120);
-                // push_log!("cleaned {} expired messages", len - recent.len());
+                // log!(Info, "cleaned {} expired messages", len - recent.len());
             }
         }
         // the AndroidWatch protocol will not receive this event
         QEvent::GroupMessageRecall(e) => {
             let recent = RECENT.lock().unwrap();
             if let Some(v) = recent.iter().find(|v| v.seqs.contains(&e.inner.msg_seq)) {
-                push_log!(
+                log!(
+                    Info,
                     r#"recalled message = {{ group: "{}", user: "{}", content: "{}" }}"#,
                     v.group_name,
                     v.group_card,
@@ -235,7 +235,7 @@ async fn on_event(event: QEvent) -> Result<()> {
                 );
             }
         }
-        QEvent::Login(e) => push_log!("login {}", e),
+        QEvent::Login(e) => log!(Info, "login {}", e),
         _ => {}
     }
     Ok(())
@@ -250,10 +250,36 @@ impl ricq::handler::Handler for MyHandler {
     }
 }
 
+/// Send the messages of chat bridges, without the `[BOT]` prefix.
+pub struct GroupSender;
+impl chat::Remote for GroupSender {
+    fn send(&self, group: i64, text: String) -> Pin<Box<dyn Future<Output = Result<()>> + Send>> {
+        Box::pin(async move {
+            if !units::enabled("qqbot") {
+                bail!("qqbot is disabled"); // avoid to init the client
+            }
+            let msg_chain = MessageChain::new(ricq::msg::elem::Text::new(text));
+            CLIENT.send_group_message(group, msg_chain).await?;
+            Ok(())
+        })
+    }
+}
+
 pub async fn notify(msg: String) -> Result<()> {
     let msg_chain = text_msg(msg);
-    for (group,) in db_groups_get() {
+    for group in super::notify_groups() {
         CLIENT.send_group_message(group, msg_chain.clone()).await?;
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn page() {
+        log!(Info, "page test");
+        assert!(get_handler().await.0.contains("page test"));
+    }
+}
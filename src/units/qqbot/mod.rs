@@ -1,15 +1,30 @@
 //! QQ robot for fun.
 mod base;
 use crate::care;
+use crate::client::{Fetch, ToRequest};
 use crate::ticker::Ticker;
-use crate::utils::{elapse, fetch_json, fetch_text, OptionResult};
+use crate::units::{self, chat};
+use crate::utils::{elapse, OptionResult};
 use anyhow::Result;
 use axum::routing::{MethodRouter, Router};
-use base::{db_groups_insert, get_handler, get_login_qr, notify, post_handler};
+pub use base::check_cfg;
+use base::{get_handler, get_login_qr, notify, post_handler, GroupSender};
 use once_cell::sync::Lazy;
 use rand::{thread_rng, Rng};
 use std::collections::HashMap;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
+
+async fn fetch_text(request: impl ToRequest) -> Result<String> {
+    Fetch::new(request).unit("qqbot").retry(1).text().await
+}
+
+async fn fetch_json(request: impl ToRequest, pointer: &str) -> Result<String> {
+    Fetch::new(request)
+        .unit("qqbot")
+        .retry(1)
+        .json(pointer)
+        .await
+}
 
 /// Generate reply from message parts
 async fn gen_reply(msg_parts: Vec<&str>) -> Result<String> {
@@ -79,12 +94,12 @@ async fn gen_reply(msg_parts: Vec<&str>) -> Result<String> {
             fetch_json(&url, "/data/info/text").await?
         }
         ["订阅通知", v] => {
-            db_groups_insert(v.parse()?);
+            notify_groups_insert(v.parse()?)?;
             format!("已为群 {v} 订阅通知")
         }
         ["取消订阅通知", _v] => {
             "鉴权还没弄好呢".into()
-            // db_groups_insert(v.parse()?);
+            // notify_groups_remove(v.parse()?)?;
             // format!("已为群 {v} 取消订阅通知")
         }
         ["设置回复", k, v] => {
@@ -132,8 +147,38 @@ fn judge_spam(msg: &str) -> bool {
     judge(msg, LIST, SENSITIVITY)
 }
 
+/// The notify groups, one group id per line.
+pub const DEFAULT_SETTINGS: &str = "";
+
+pub fn check_settings(v: &str) -> Result<()> {
+    for line in v.lines().map(str::trim).filter(|v| !v.is_empty()) {
+        line.parse::<i64>()?;
+    }
+    Ok(())
+}
+
+fn notify_groups() -> Vec<i64> {
+    let settings = units::settings("qqbot");
+    settings
+        .lines()
+        .filter_map(|v| v.trim().parse().ok())
+        .collect()
+}
+
+fn notify_groups_insert(group_id: i64) -> Result<()> {
+    let mut groups = notify_groups();
+    if !groups.contains(&group_id) {
+        groups.push(group_id);
+    }
+    let groups = groups.iter().map(|v| v.to_string()).collect::<Vec<_>>();
+    units::set_settings("qqbot", &groups.join("\n"))
+}
+
 pub fn service() -> Router {
-    get_login_qr(); // init client
+    if units::enabled("qqbot") {
+        get_login_qr(); // init client
+    }
+    chat::set_remote(Arc::new(GroupSender));
     Router::new()
         .route(
             "/qqbot",
@@ -1,13 +1,14 @@
+use crate::log;
+use crate::utils::CompressedPage;
 use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
 use axum::extract::Path;
-use axum::response::Html;
+use axum::http::HeaderMap;
 use axum::routing::MethodRouter;
 use axum::Router;
-use tokio::fs;
 
 async fn ws_handler(mut ws: WebSocket) {
     while let Some(Ok(Message::Binary(v))) = ws.recv().await {
-        println!("ws.recv() : {}", v.len());
+        log!(Debug, "ws.recv() : {}", v.len());
     }
 }
 
@@ -15,13 +16,16 @@ pub fn service() -> Router {
     Router::new()
         .route(
             "/record",
-            MethodRouter::new().get(|| async { Html(include_str!("page.html")) }),
+            MethodRouter::new().get(|headers: HeaderMap| async move {
+                static PAGE: CompressedPage =
+                    CompressedPage::from_static(include_str!("page.html"));
+                PAGE.respond(&headers)
+            }),
         )
         .route(
             "/record/ws/:id",
-            MethodRouter::new().get(|id: Path<String>, u: WebSocketUpgrade| {
-                //
-                async { u.on_upgrade(ws_handler) }
+            MethodRouter::new().get(|_id: Path<String>, u: WebSocketUpgrade| async {
+                crate::tls::upgrade(u, ws_handler)
             }),
         )
         .layer(crate::auth::auth_layer())
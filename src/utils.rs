@@ -1,11 +1,21 @@
 use anyhow::Result;
-use hyper::body::HttpBody;
-use hyper::client::HttpConnector;
-use hyper::{Body, Client, Request, Response};
-use hyper_rustls::HttpsConnector;
-use once_cell::sync::Lazy;
+use axum::body::{BoxBody, Bytes};
+use axum::http::header::{HeaderMap, HeaderValue, ACCEPT_ENCODING, CONTENT_ENCODING, VARY};
+use axum::response::sse::Event;
+use axum::response::{Html, IntoResponse};
+use flate2::read::{GzEncoder, ZlibEncoder};
+use flate2::Compression;
+use futures_core::{ready, Stream};
+use hyper::Response;
+use once_cell::sync::OnceCell;
+use std::convert::Infallible;
+use std::future::Future;
+use std::io::Read;
+use std::pin::Pin;
+use std::task::{Context, Poll};
 use std::time::UNIX_EPOCH;
-use tokio_rustls::rustls::{ClientConfig, OwnedTrustAnchor, RootCertStore};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::broadcast::Receiver;
 
 pub trait OptionResult<T> {
     fn e(self) -> Result<T>;
@@ -58,111 +68,211 @@ pub fn encode_uri(i: &str) -> String {
     unsafe { String::from_utf8_unchecked(o) }
 }
 
-/// Escape log string into a single line, html safe string.
-///
-/// `[a"foo\nbar]` into `[a&quot;foo\\nbar]`
-pub fn log_escape(s: &str) -> String {
-    askama_escape::escape(&s.replace('\n', "\\n"), askama_escape::Html).to_string()
+const BASE64_CHARS: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Standard base64 with padding.
+pub fn base64_encode(input: &[u8]) -> String {
+    let mut ret = String::new();
+    for chunk in input.chunks(3) {
+        let v = (chunk[0] as u32) << 16
+            | (*chunk.get(1).unwrap_or(&0) as u32) << 8
+            | *chunk.get(2).unwrap_or(&0) as u32;
+        for i in 0..4 {
+            match i <= chunk.len() {
+                true => ret.push(BASE64_CHARS[(v >> (18 - i * 6)) as usize & 63] as char),
+                false => ret.push('='),
+            }
+        }
+    }
+    ret
 }
 
-/// Read `hyper::Body` into `Vec<u8>`, returns emply if reached the limit size (2 MiB).
-///
-/// Simpler than `hyper::body::to_bytes`.
-pub async fn read_body(mut body: Body) -> Vec<u8> {
-    // TODO: reimplement?
-    let mut v = Vec::new();
-    while let Some(Ok(bytes)) = body.data().await {
-        v.append(&mut bytes.into());
-        // 2 MiB
-        if v.len() > 2048 * 1024 {
-            v.clear();
-            break;
+/// Decode standard base64, whitespaces are ignored, padding is optional.
+pub fn base64_decode(input: &str) -> Option<Vec<u8>> {
+    let mut ret = Vec::new();
+    let (mut acc, mut bits) = (0u32, 0);
+    let input = input.bytes().filter(|v| !v.is_ascii_whitespace());
+    for b in input.take_while(|&v| v != b'=') {
+        acc = acc << 6 | BASE64_CHARS.iter().position(|&v| v == b)? as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            ret.push((acc >> bits) as u8);
         }
     }
-    v
+    Some(ret)
 }
 
-static CLIENT: Lazy<Client<HttpsConnector<HttpConnector>>> = Lazy::new(|| {
-    // https://github.com/seanmonstar/reqwest/blob/v0.11.11/src/async_impl/client.rs#L340
-    let root_cert_store = RootCertStore {
-        roots: { webpki_roots::TLS_SERVER_ROOTS.0.iter() }
-            .map(|trust_anchor| {
-                OwnedTrustAnchor::from_subject_spki_name_constraints(
-                    trust_anchor.subject,
-                    trust_anchor.spki,
-                    trust_anchor.name_constraints,
-                )
-            })
-            .collect(),
-    };
-    let tls_cfg = ClientConfig::builder()
-        .with_safe_defaults()
-        .with_root_certificates(root_cert_store)
-        .with_no_client_auth();
-    // tls_cfg.alpn_protocols = vec![b"http/1.1".to_vec()]; // http2 is not supported
-    let mut http_conn = HttpConnector::new();
-    http_conn.enforce_http(false); // allow HTTPS
-    let connector = HttpsConnector::from((http_conn, tls_cfg));
-    Client::builder().build(connector)
-});
-
-pub trait ToRequest {
-    fn into_request(self) -> Request<Body>;
+/// Escape log string into a single line, the html escaping is done by `template!`.
+///
+/// `[a"foo\nbar]` into `[a"foo\\nbar]`
+pub fn log_escape(s: &str) -> String {
+    s.replace('\n', "\\n")
 }
-impl ToRequest for Request<Body> {
-    fn into_request(self) -> Request<Body> {
-        self
-    }
+
+#[allow(unused)]
+pub use crate::client::{fetch, fetch_json, fetch_text};
+
+type RecvStreamFut<T> = Pin<Box<dyn Future<Output = (Option<T>, Receiver<T>)> + Send>>;
+
+/// Wrap `broadcast::Receiver` as a SSE `Stream`, the lagged values are skipped.
+///
+/// # Example
+///
+/// ```
+/// Sse::new(RecvStream::new(tx.subscribe(), |v| Event::default().data(v)))
+/// ```
+pub struct RecvStream<T> {
+    fut: RecvStreamFut<T>,
+    to_event: Box<dyn Fn(T) -> Option<Event> + Send>,
 }
-impl ToRequest for &str {
-    fn into_request(self) -> Request<Body> {
-        let ret = Request::get(encode_uri(self)).body(Body::empty()).unwrap();
-        ret.into_request()
+
+impl<T: Clone + Send + 'static> RecvStream<T> {
+    pub fn new(rx: Receiver<T>, to_event: fn(T) -> Event) -> Self {
+        Self::filter_map(rx, move |v| Some(to_event(v)))
     }
-}
-impl ToRequest for &String {
-    fn into_request(self) -> Request<Body> {
-        self.as_str().into_request()
+
+    /// Like `new`, but the values mapped to `None` are skipped.
+    pub fn filter_map(
+        rx: Receiver<T>,
+        to_event: impl Fn(T) -> Option<Event> + Send + 'static,
+    ) -> Self {
+        RecvStream {
+            fut: Self::make_fut(rx),
+            to_event: Box::new(to_event),
+        }
+    }
+
+    fn make_fut(mut rx: Receiver<T>) -> RecvStreamFut<T> {
+        Box::pin(async {
+            loop {
+                match rx.recv().await {
+                    Ok(v) => return (Some(v), rx),
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => return (None, rx),
+                }
+            }
+        })
     }
 }
 
-/// Send a `Request` and return the response. Allow both HTTPS and HTTP.
-///
-/// Unlike `reqwest` crate, this function dose not follow redirect.
-pub async fn fetch(request: impl ToRequest) -> Result<Response<Body>> {
-    Ok(CLIENT.request(request.into_request()).await?)
+impl<T: Clone + Send + 'static> Stream for RecvStream<T> {
+    type Item = Result<Event, Infallible>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            let (value, rx) = ready!(Pin::new(&mut this.fut).poll(cx));
+            this.fut = Self::make_fut(rx);
+            match value.map(&this.to_event) {
+                Some(None) => continue, // skipped
+                Some(Some(v)) => return Poll::Ready(Some(Ok(v))),
+                None => return Poll::Ready(None),
+            }
+        }
+    }
 }
 
-/// Fetch a URI, returns as text.
-pub async fn fetch_text(request: impl ToRequest) -> Result<String> {
-    // let request = request.into_request();
-    // let a = format!("{}", request.uri());
-    // println!("begin:  {a}");
-    let response = fetch(request).await?;
-    let body = read_body(response.into_body()).await;
-    // println!("finish: {a}");
-    Ok(String::from_utf8(body)?)
+/// Pick the best encoding by `Accept-Encoding`, returns `identity` if nothing supported.
+pub fn negotiate_encoding(headers: &HeaderMap) -> &'static str {
+    let accept = headers.get(ACCEPT_ENCODING).and_then(|v| v.to_str().ok());
+    let accept = accept.unwrap_or_default();
+    let q_of = |name| {
+        let mut items = accept.split(',').map(|v| v.split(';'));
+        let params = items.find(|v| v.clone().next().map(str::trim) == Some(name));
+        let mut params = params?.skip(1);
+        Some(
+            params
+                .find_map(|v| v.trim().strip_prefix("q=")?.parse().ok())
+                .unwrap_or(1.0),
+        )
+    };
+    let mut best = ("identity", 0.0);
+    for name in ["br", "gzip", "deflate"] {
+        match q_of(name) {
+            Some(q) if q > best.1 => best = (name, q),
+            _ => {}
+        }
+    }
+    best.0
 }
 
-/// Fetch a URI which response json, get field by pointer.
+/// A html page with compressed forms cached, the response is negotiated by `Accept-Encoding`.
+///
+/// The router-wide `CompressionLayer` skips responses that have `Content-Encoding`, so pages
+/// are compressed only once.
 ///
-/// # Examples
+/// # Example
 ///
 /// ```
-/// // value will be convert to string, the field type is not cared
-/// let v = await fetch_json("https://api.io", "/data/size"));
-/// // is this? { "data": { "size": "1024" } }
-/// // or this? { "data": { "size": 1024 } }
-/// assert_eq!(v, Ok("1024".to_string())); // the same result!
+/// static PAGE: CompressedPage = CompressedPage::from_static("<h1>Hi</h1>");
+/// MethodRouter::new().get(|h: HeaderMap| async move { PAGE.respond(&h) })
 /// ```
-pub async fn fetch_json(request: impl ToRequest, pointer: &str) -> Result<String> {
-    let text = fetch_text(request).await?;
-    let v = serde_json::from_str::<serde_json::Value>(&text)?;
-    let v = v
-        .pointer(pointer)
-        .ok_or_else(|| anyhow::anyhow!("json field not found"))?
-        .to_string();
-    Ok(v.trim_matches('"').to_owned())
+pub struct CompressedPage {
+    raw: Bytes,
+    br: OnceCell<Bytes>,
+    gzip: OnceCell<Bytes>,
+    deflate: OnceCell<Bytes>,
+}
+
+impl CompressedPage {
+    pub const fn from_static(raw: &'static str) -> Self {
+        CompressedPage {
+            raw: Bytes::from_static(raw.as_bytes()),
+            br: OnceCell::new(),
+            gzip: OnceCell::new(),
+            deflate: OnceCell::new(),
+        }
+    }
+
+    pub fn new(raw: impl Into<Bytes>) -> Self {
+        CompressedPage {
+            raw: raw.into(),
+            br: OnceCell::new(),
+            gzip: OnceCell::new(),
+            deflate: OnceCell::new(),
+        }
+    }
+
+    fn get(&self, encoding: &str) -> Bytes {
+        let raw = self.raw.as_ref();
+        let compress = |mut enc: Box<dyn Read + '_>| {
+            let mut buf = Vec::new();
+            enc.read_to_end(&mut buf).unwrap();
+            Bytes::from(buf)
+        };
+        match encoding {
+            "br" => self.br.get_or_init(|| {
+                compress(Box::new(brotli::CompressorReader::new(raw, 4096, 11, 22)))
+            }),
+            "gzip" => self
+                .gzip
+                .get_or_init(|| compress(Box::new(GzEncoder::new(raw, Compression::best())))),
+            "deflate" => self
+                .deflate
+                .get_or_init(|| compress(Box::new(ZlibEncoder::new(raw, Compression::best())))),
+            _ => &self.raw,
+        }
+        .clone() // `bytes::Bytes` is cheap on clone
+    }
+
+    /// Compress into all encodings now, call this in `spawn_blocking` for large pages.
+    pub fn warm(&self) {
+        for encoding in ["br", "gzip", "deflate"] {
+            self.get(encoding);
+        }
+    }
+
+    pub fn respond(&self, headers: &HeaderMap) -> Response<BoxBody> {
+        let encoding = negotiate_encoding(headers);
+        let mut ret = Html(self.get(encoding)).into_response();
+        let headers = ret.headers_mut();
+        if encoding != "identity" {
+            headers.insert(CONTENT_ENCODING, HeaderValue::from_static(encoding));
+        }
+        headers.insert(VARY, HeaderValue::from_static("accept-encoding"));
+        ret
+    }
 }
 
 /// (stamp secs) -> (days)
@@ -173,12 +283,12 @@ pub fn elapse(stamp: f64) -> f64 {
 }
 
 #[macro_export]
-/// Care about the `Result`.
+/// Care about the `Result`, the error is logged.
 macro_rules! care {
     ($result:expr) => {{
         let result = $result;
         if let Err(e) = &result {
-            eprintln!("[cared error] {}:{} {:?}", file!(), line!(), e);
+            $crate::log!(Error, "{}:{} {:?}", file!(), line!(), e);
         }
         result
     }};
@@ -381,7 +491,7 @@ pub fn _test_include_page() {
 /// Detect the `strip_str` works or not.
 pub fn _detect_str_in_binary() {
     let s = std::fs::read("ksite").unwrap();
-    let p = b"DELETE FROM health_log";
+    let p = b"DELETE FROM access_log";
     for i in 0..s.len() {
         let mut m = true;
         for j in 0..p.len() {